@@ -0,0 +1,196 @@
+use crate::errors::OAuthError;
+use crate::token::{self, TokenParameters, TokenResponse};
+use crate::AppState;
+use actix_web::HttpRequest;
+use biscuit::{Empty, JWT};
+use serde_json::{json, Value};
+
+/// The JWT bearer assertion grant of RFC 7523 section 2.1.
+/// The assertion has to be signed by a trusted issuer and is exchanged for an access token of its subject.
+pub fn jwt_bearer_grant(
+    state: &AppState,
+    req: &HttpRequest,
+    params: TokenParameters,
+) -> Result<TokenResponse, OAuthError> {
+    // Client authentication is optional for this grant, see RFC 7523 section 3.1.
+    let client_id =
+        if params.client.client_id.is_some() || req.headers().contains_key("Authorization") {
+            Some(token::authenticate_client(state, req, &params.client)?)
+        } else {
+            None
+        };
+    let assertion = params
+        .assertion
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("assertion is missing"))?;
+
+    // The issuer has to be known before the signature can be checked with its keys.
+    let unverified = JWT::<Value, Empty>::new_encoded(assertion)
+        .unverified_payload()
+        .map_err(|_| OAuthError::invalid_grant("assertion is not a JWT"))?;
+    let issuer = unverified
+        .registered
+        .issuer
+        .ok_or_else(|| OAuthError::invalid_grant("assertion has no iss claim"))?;
+    let trusted = state
+        .config
+        .trusted_issuer(&issuer)
+        .ok_or_else(|| OAuthError::invalid_grant(format!("issuer {} is not trusted", issuer)))?;
+    let claims = token::verify_jwt(&trusted.jwks, assertion)
+        .map_err(|_| OAuthError::invalid_grant("assertion signature is invalid"))?;
+
+    let sub = claims["sub"]
+        .as_str()
+        .ok_or_else(|| OAuthError::invalid_grant("assertion has no sub claim"))?;
+    let token_endpoint = format!("{}/token", state.exposed_host);
+    let audiences = match &claims["aud"] {
+        Value::String(aud) => vec![aud.as_str()],
+        Value::Array(auds) => auds.iter().filter_map(|aud| aud.as_str()).collect(),
+        _ => vec![],
+    };
+    if !audiences
+        .iter()
+        .any(|aud| *aud == token_endpoint || *aud == state.exposed_host)
+    {
+        return Err(OAuthError::invalid_grant(format!(
+            "assertion aud has to contain {}",
+            token_endpoint
+        )));
+    }
+    let exp = claims["exp"]
+        .as_u64()
+        .ok_or_else(|| OAuthError::invalid_grant("assertion has no exp claim"))?;
+    let now = token::now();
+    if exp < now {
+        return Err(OAuthError::invalid_grant("assertion is expired"));
+    }
+    if let Some(jti) = claims["jti"].as_str() {
+        // An assertion id is only remembered until the assertion expires, after that it cannot be replayed anyway.
+        let mut assertion_ids = state.assertion_ids.lock().unwrap();
+        assertion_ids.retain(|_, expires_at| *expires_at >= now);
+        if assertion_ids
+            .insert(format!("{} {}", issuer, jti), exp)
+            .is_some()
+        {
+            return Err(OAuthError::invalid_grant("assertion was already used"));
+        }
+    }
+
+    let iat = now;
+    let mut access_claims = json!(
+        {
+            "iss": state.exposed_host,
+            "sub": sub,
+            "aud": client_id.as_deref().unwrap_or(&issuer),
+            "iat": iat,
+            "exp": iat + token::TOKEN_LIFETIME
+        }
+    );
+    if let Some(client_id) = &client_id {
+        access_claims["client_id"] = json!(client_id);
+    }
+    if let Some(scope) = &params.scope {
+        access_claims["scope"] = json!(scope);
+    }
+
+    Ok(TokenResponse {
+        access_token: token::create_access_token_jwt(&state.rsa_key_pair, access_claims),
+        token_type: "Bearer",
+        expires_in: token::TOKEN_LIFETIME,
+        id_token: None,
+        refresh_token: None,
+        scope: params.scope,
+        issued_token_type: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, TrustedIssuer};
+    use crate::discovery::create_jwk_set;
+    use crate::token::create_token;
+    use actix_web::{http, test, web, App, Error};
+    use biscuit::jws::Secret;
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            trusted_issuers: vec![TrustedIssuer {
+                issuer: "https://partner.example.com".to_string(),
+                jwks: create_jwk_set(rsa_keys.clone()),
+            }],
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    fn create_assertion_request(state: &AppState, aud: &str) -> test::TestRequest {
+        let assertion = token::create_jwt(
+            &state.rsa_key_pair,
+            json!({
+                "iss": "https://partner.example.com",
+                "sub": "arie",
+                "aud": aud,
+                "exp": token::now() + 60,
+                "jti": "assertion-1"
+            }),
+        );
+        test::TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(format!(
+                "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer&assertion={}",
+                assertion
+            ))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_jwt_bearer() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = create_assertion_request(&state, "http://localhost:8080/token").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let p: Value = test::read_body_json(resp).await;
+        let access_token = JWT::<Value, Empty>::new_encoded(p["access_token"].as_str().unwrap());
+        let claims = serde_json::to_value(access_token.unverified_payload().unwrap()).unwrap();
+        assert_eq!(claims["sub"], "arie");
+
+        // The same jti cannot be used twice.
+        let req = create_assertion_request(&state, "http://localhost:8080/token").to_request();
+        let resp = test::call_service(&app, req).await;
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_grant");
+        assert_eq!(p["error_description"], "assertion was already used");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_jwt_bearer_wrong_audience() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = create_assertion_request(&state, "https://other.example.com").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_grant");
+
+        Ok(())
+    }
+}
@@ -1,101 +1,1236 @@
+use crate::claims::ClaimsRequest;
+use crate::errors::OAuthError;
+use crate::jar;
+use crate::logout;
+use crate::par;
+use crate::response::{self, ResponseMode};
+use crate::session::{self, Session};
+use crate::token::{self, Authorization};
 use crate::AppState;
 use actix_web::http::StatusCode;
-use actix_web::{web, Error, HttpResponse};
-use data_encoding::BASE64URL_NOPAD;
-use der_parser::nom::Slice;
-use ring::digest;
-use serde_derive::Deserialize;
-use serde_json::json;
-use std::ops::Add;
-use std::time::SystemTime;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Deserialize)]
+/// Lifetime in seconds of an authorization code before it has to be exchanged at the token endpoint.
+pub const CODE_LIFETIME: u64 = 60;
+
+/// The response types of the code, implicit and hybrid flows, the order of the values in a request does not matter.
+pub const RESPONSE_TYPES: [&str; 7] = [
+    "code",
+    "id_token",
+    "token",
+    "id_token token",
+    "code id_token",
+    "code token",
+    "code id_token token",
+];
+
+#[derive(Deserialize, Serialize)]
 pub struct AuthParameters {
-    client_id: String,
+    pub client_id: String,
     redirect_uri: String,
     response_type: String,
     scope: String,
     state: String,
-    nonce: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    response_mode: Option<String>,
+    prompt: Option<String>,
+    max_age: Option<String>,
+    acr_values: Option<String>,
+    login_hint: Option<String>,
+    id_token_hint: Option<String>,
+    claims: Option<String>,
+}
+
+/// An authorization code that is waiting to be exchanged at the token endpoint.
+pub struct AuthorizationCode {
+    pub authorization: Authorization,
+    pub redirect_uri: String,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub expires_at: u64,
+}
+
+/// The parameters of an authorization request that passes the request in a request object,
+/// by value or by reference, or refers to a request pushed to the /par endpoint.
+#[derive(Deserialize)]
+struct RequestReference {
+    client_id: Option<String>,
+    request: Option<String>,
+    request_uri: Option<String>,
 }
 
-pub async fn auth(info: web::Query<AuthParameters>) -> Result<HttpResponse, Error> {
-    let body = format!(
+pub async fn auth(app_state: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    let reference = web::Query::<RequestReference>::from_query(req.query_string())?;
+    let (info, pushed) = match (&reference.request, &reference.request_uri) {
+        (Some(_), Some(_)) => {
+            return Err(OAuthError::invalid_request(
+                "request and request_uri cannot be used together",
+            )
+            .into())
+        }
+        (Some(request), None) => (
+            jar::authorization_request(&app_state, req.query_string(), request)?,
+            false,
+        ),
+        (None, Some(request_uri)) if par::is_pushed(request_uri) => (
+            par::pushed_request(
+                &app_state,
+                reference.client_id.as_deref().unwrap_or_default(),
+                request_uri,
+            )?,
+            true,
+        ),
+        (None, Some(request_uri)) => {
+            let request = jar::fetch(&app_state, request_uri).await?;
+            (
+                jar::authorization_request(&app_state, req.query_string(), &request)?,
+                false,
+            )
+        }
+        (None, None) => (
+            web::Query::<AuthParameters>::from_query(req.query_string())?.into_inner(),
+            false,
+        ),
+    };
+
+    let response_mode = match info.validate() {
+        Ok(response_mode) => response_mode,
+        Err((response_mode, error)) => {
+            return Ok(error_response(&app_state, &info, response_mode, error))
+        }
+    };
+    let requires_par = app_state
+        .config
+        .client(&info.client_id)
+        .is_some_and(|client| client.require_pushed_authorization_requests);
+    if requires_par && !pushed {
+        return Ok(error_response(
+            &app_state,
+            &info,
+            response_mode,
+            OAuthError::invalid_request("the client has to push its authorization request to /par"),
+        ));
+    }
+
+    // Prompts are handled as in OIDC Core section 3.1.2.1, prompt=login needs nothing more than showing the login page.
+    let hinted = match info.hinted_user(&app_state) {
+        Ok(hinted) => hinted,
+        Err(error) => return Ok(error_response(&app_state, &info, response_mode, error)),
+    };
+    // A session of another user than the hinted one cannot be used, that user has to log in.
+    let session = session::current(&app_state, &req).filter(|(_, session)| {
+        info.satisfied_by(session) && hinted.as_ref().is_none_or(|(sub, _)| *sub == session.sub)
+    });
+    if info.prompts("none") {
+        return Ok(match session {
+            None => error_response(
+                &app_state,
+                &info,
+                response_mode,
+                OAuthError::new("login_required", "there is no session to log in silently"),
+            ),
+            Some((_, session)) if info.needs_consent(&app_state, &session) => error_response(
+                &app_state,
+                &info,
+                response_mode,
+                OAuthError::new("interaction_required", "the user has to consent first"),
+            ),
+            Some((_, session)) => authorize(&app_state, &info, response_mode, &session),
+        });
+    }
+    if info.prompts("select_account") {
+        return Ok(select_account_page(
+            &app_state,
+            &info,
+            session.map(|(_, session)| session),
+        ));
+    }
+    // Single sign-on: the user of the session continues without the login page, unless the client asks for a login.
+    if let Some((_, session)) = session.filter(|_| !info.prompts("login")) {
+        return Ok(if info.needs_consent(&app_state, &session) {
+            consent_page(&info)
+        } else {
+            authorize(&app_state, &info, response_mode, &session)
+        });
+    }
+    // The first of the requested acr_values is the preferred one, see OIDC Core section 3.1.2.1.
+    let acr = info
+        .acr_values
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .next()
+        .unwrap_or_default();
+    let (sub, name) = hinted.unwrap_or_default();
+    Ok(page(format!(
         include_str!("../template/login.html"),
-        state = info.state,
-        redirect_uri = info.redirect_uri,
-        nonce = info.nonce,
-        client_id = info.client_id
-    );
-    Ok(HttpResponse::build(StatusCode::OK)
+        hidden_fields = hidden_fields(&info),
+        sub = escape_html(&sub),
+        name = escape_html(&name),
+        acr = escape_html(acr)
+    )))
+}
+
+impl AuthParameters {
+    /// Checks the authorization request, an error comes with the response_mode to return it in.
+    pub fn validate(&self) -> Result<ResponseMode, (ResponseMode, OAuthError)> {
+        let default_mode = ResponseMode::default_for(&self.response_type);
+        if !self.has_supported_response_type() {
+            return Err((
+                default_mode,
+                OAuthError::new(
+                    "unsupported_response_type",
+                    "response_type is not supported",
+                ),
+            ));
+        }
+        let response_mode = self
+            .response_mode()
+            .map_err(|description| (default_mode, OAuthError::invalid_request(description)))?;
+        if self.returns("id_token") && self.nonce.as_deref().unwrap_or_default().is_empty() {
+            // An ID token from the authorization endpoint can only be tied to the session of the client by its nonce.
+            return Err((
+                response_mode,
+                OAuthError::invalid_request("nonce is required for a response_type with id_token"),
+            ));
+        }
+        if self.prompts("none")
+            && self
+                .prompt
+                .iter()
+                .flat_map(|p| p.split_whitespace())
+                .count()
+                > 1
+        {
+            return Err((
+                response_mode,
+                OAuthError::invalid_request("prompt none cannot be combined with other values"),
+            ));
+        }
+        if let Err(description) = self.claims_request() {
+            return Err((response_mode, OAuthError::invalid_request(description)));
+        }
+        if self.max_age().is_err() {
+            return Err((
+                response_mode,
+                OAuthError::invalid_request("max_age is not a number of seconds"),
+            ));
+        }
+        if let Some(method) = &self.code_challenge_method {
+            if !token::PKCE_METHODS.contains(&method.as_str()) {
+                return Err((
+                    response_mode,
+                    OAuthError::invalid_request("code_challenge_method is not supported"),
+                ));
+            }
+        }
+        Ok(response_mode)
+    }
+
+    fn claims_request(&self) -> Result<Option<ClaimsRequest>, String> {
+        self.claims
+            .as_deref()
+            .filter(|claims| !claims.is_empty())
+            .map(ClaimsRequest::parse)
+            .transpose()
+    }
+
+    fn max_age(&self) -> Result<Option<u64>, std::num::ParseIntError> {
+        self.max_age
+            .as_deref()
+            .filter(|max_age| !max_age.is_empty())
+            .map(str::parse)
+            .transpose()
+    }
+
+    /// Whether the login of the session is recent and strong enough for this request, otherwise the user logs in again.
+    fn satisfied_by(&self, session: &Session) -> bool {
+        let recent = match self.max_age() {
+            Ok(Some(max_age)) => token::now().saturating_sub(session.auth_time) < max_age,
+            _ => true,
+        };
+        let acr_values = self.acr_values.as_deref().unwrap_or_default();
+        let strong = acr_values.trim().is_empty()
+            || session
+                .acr
+                .as_deref()
+                .is_some_and(|acr| token::has_scope(acr_values, acr));
+        recent && strong
+    }
+
+    /// The sub and name of the user the client expects to log in, from a verified id_token_hint or the login_hint.
+    /// A login_hint of a registered user is its username or sub, any other hint is taken as the subject.
+    fn hinted_user(&self, state: &AppState) -> Result<Option<(String, String)>, OAuthError> {
+        if let Some(id_token_hint) = self
+            .id_token_hint
+            .as_deref()
+            .filter(|hint| !hint.is_empty())
+        {
+            let claims = token::verify_issued_jwt(state, id_token_hint).ok_or_else(|| {
+                OAuthError::invalid_request("id_token_hint is not an ID token issued by fakeidp")
+            })?;
+            let sub = claims["sub"]
+                .as_str()
+                .ok_or_else(|| OAuthError::invalid_request("id_token_hint has no sub claim"))?;
+            // The name is only in an ID token with the profile scope, otherwise the registered user has it.
+            let name = match claims["name"].as_str() {
+                Some(name) => name.to_string(),
+                None => hinted_name(state, sub),
+            };
+            return Ok(Some((sub.to_string(), name)));
+        }
+        Ok(self
+            .login_hint
+            .as_deref()
+            .filter(|hint| !hint.is_empty())
+            .map(|hint| {
+                match state
+                    .config
+                    .users
+                    .iter()
+                    .find(|user| user.username == hint || user.sub == hint)
+                {
+                    Some(user) => (user.sub.clone(), user.name.clone()),
+                    None => (hint.to_string(), String::new()),
+                }
+            }))
+    }
+
+    fn prompts(&self, prompt: &str) -> bool {
+        token::has_scope(self.prompt.as_deref().unwrap_or_default(), prompt)
+    }
+
+    /// The consent screen is shown when the client asks for it, or once per session for a client that requires it.
+    fn needs_consent(&self, state: &AppState, session: &Session) -> bool {
+        let required = state
+            .config
+            .client(&self.client_id)
+            .is_some_and(|client| client.require_consent);
+        self.prompts("consent") || (required && !session.consents.contains(&self.client_id))
+    }
+
+    fn returns(&self, response_type: &str) -> bool {
+        token::has_scope(&self.response_type, response_type)
+    }
+
+    fn has_supported_response_type(&self) -> bool {
+        let mut requested: Vec<&str> = self.response_type.split_whitespace().collect();
+        requested.sort_unstable();
+        RESPONSE_TYPES.iter().any(|supported| {
+            let mut supported: Vec<&str> = supported.split_whitespace().collect();
+            supported.sort_unstable();
+            supported == requested
+        })
+    }
+
+    fn response_mode(&self) -> Result<ResponseMode, String> {
+        ResponseMode::from_request(self.response_mode.as_deref(), &self.response_type)
+    }
+}
+
+/// Returns an error to the client as described in RFC 6749 section 4.1.2.1.
+fn error_response(
+    state: &AppState,
+    request: &AuthParameters,
+    response_mode: ResponseMode,
+    error: OAuthError,
+) -> HttpResponse {
+    response::respond(
+        state,
+        &request.client_id,
+        &request.redirect_uri,
+        response_mode,
+        &[
+            ("error", error.error),
+            (
+                "error_description",
+                error.error_description.as_deref().unwrap_or_default(),
+            ),
+            ("state", &request.state),
+        ],
+    )
+}
+
+/// The name of the registered user with the sub, empty for an unknown user.
+pub fn hinted_name(state: &AppState, sub: &str) -> String {
+    state
+        .config
+        .user_by_sub(sub)
+        .map(|user| user.name.clone())
+        .unwrap_or_default()
+}
+
+/// Renders the authorization request as hidden inputs, so the login form posts it back to us.
+fn hidden_fields(params: &AuthParameters) -> String {
+    match serde_json::to_value(params) {
+        Ok(Value::Object(fields)) => fields
+            .iter()
+            .filter_map(|(name, value)| value.as_str().map(|value| (name, value)))
+            .map(|(name, value)| hidden_field(name, value))
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+/// Escapes a value to be placed inside an HTML attribute or element.
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+/// A hidden input of a form that posts the value back.
+pub(crate) fn hidden_field(name: &str, value: &str) -> String {
+    format!(
+        "<input type=\"hidden\" name=\"{}\" value=\"{}\" />\n",
+        escape_html(name),
+        escape_html(value)
+    )
+}
+
+fn page(body: String) -> HttpResponse {
+    HttpResponse::build(StatusCode::OK)
         .content_type("text/html; charset=utf-8")
-        .body(body))
+        .body(body)
+}
+
+/// Lets the user continue with the account of the session or one of the registered users, or log in as another.
+fn select_account_page(
+    state: &AppState,
+    request: &AuthParameters,
+    session: Option<Session>,
+) -> HttpResponse {
+    let hidden_fields = hidden_fields(request);
+    let accounts: String = session
+        .map(|session| (session.sub, session.name))
+        .into_iter()
+        .chain(
+            state
+                .config
+                .users
+                .iter()
+                .filter(|user| !user.disabled)
+                .map(|user| (user.sub.clone(), user.name.clone())),
+        )
+        .map(|(sub, name)| {
+            format!(
+                "<form method=\"post\" action=\"/auth/login\">\n{}{}{}<button type=\"submit\" class=\"idp-btn theme-btn--primary\">{}</button>\n</form>\n",
+                hidden_fields,
+                hidden_field("sub", &sub),
+                hidden_field("name", &name),
+                escape_html(&name)
+            )
+        })
+        .collect();
+    page(format!(
+        include_str!("../template/select_account.html"),
+        accounts = accounts,
+        hidden_fields = hidden_fields
+    ))
+}
+
+fn consent_page(request: &AuthParameters) -> HttpResponse {
+    let scopes: String = request
+        .scope
+        .split_whitespace()
+        .map(|scope| format!("<li>{}</li>\n", escape_html(scope)))
+        .collect();
+    page(format!(
+        include_str!("../template/consent.html"),
+        client_id = escape_html(&request.client_id),
+        scopes = scopes,
+        hidden_fields = hidden_fields(request)
+    ))
 }
 
 #[derive(Deserialize)]
 pub struct LoginParameters {
-    state: String,
-    client_id: String,
-    redirect_uri: String,
+    #[serde(flatten)]
+    request: AuthParameters,
     sub: String,
-    nonce: String,
     name: String,
+    acr: Option<String>,
+    amr: Option<String>,
 }
+
+/// Logs the user in with the subject and name of the login page and starts a new session for the browser.
+/// The same user logging in again keeps the session, another user ends it and its clients are logged out.
 pub async fn login(
     app_state: web::Data<AppState>,
+    req: HttpRequest,
     form: web::Form<LoginParameters>,
 ) -> Result<HttpResponse, Error> {
-    let signing_secret = &app_state.rsa_key_pair;
-
-    // Setup a a series of claims and corrections.
-    let iat = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let exp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .add(std::time::Duration::from_secs(12200))
-        .as_secs();
-
-    // Create the access token
-    let access_claims = json!(
-        {
-            "iss": app_state.exposed_host,
-            "sub": form.sub,
-            "aud": form.client_id,
-            "name": form.name,
-            "iat": iat,
-            "exp": exp
+    let current = session::current(&app_state, &req);
+    let replaced = current
+        .as_ref()
+        .filter(|(_, session)| session.sub != form.sub)
+        .and_then(|(id, _)| session::end(&app_state, id));
+    if let Some(replaced) = replaced {
+        let frontchannel_logouts = logout::frontchannel_logout_uris(&app_state, &replaced);
+        // The login does not wait for the clients, their logout tokens are delivered in the background.
+        let state = app_state.clone();
+        actix_web::rt::spawn(async move {
+            logout::backchannel_logout(&state, &replaced).await;
+        });
+        if !frontchannel_logouts.is_empty() {
+            return Ok(switch_user_page(&form, &frontchannel_logouts));
         }
-    );
-    let access_token = crate::token::create_jwt(signing_secret, access_claims);
-
-    // at_hash. Access Token hash value.
-    // Its value is the base64url encoding of the left-most half of the hash of the octets of the ASCII representation of the access_token value,
-    // where the hash algorithm used is the hash algorithm used in the alg Header Parameter of the ID Token's JOSE Header.
-    // For instance, if the alg is RS256, hash the access_token value with SHA-256, then take the left-most 128 bits and base64url encode them. (without padding)
-    // The at_hash value is a case sensitive string.
-    let sha_digest = digest::digest(&digest::SHA256, access_token.as_bytes());
-    let tb_encoded = sha_digest.as_ref();
-    let at_hash = BASE64URL_NOPAD.encode(tb_encoded.slice(0..16));
-
-    let id_claims = json!(
-        {
-            "iss": app_state.exposed_host,
-            "sub": form.sub,
-            "aud": form.client_id,
-            "name": form.name,
-            "iat": iat,
-            "exp": exp,
-            "nonce": form.nonce,
-            "at_hash": at_hash
+    }
+    let LoginParameters {
+        request,
+        sub,
+        name,
+        acr,
+        amr,
+    } = form.into_inner();
+    let response_mode = request
+        .response_mode()
+        .unwrap_or_else(|_| ResponseMode::default_for(&request.response_type));
+    let amr: Vec<String> = amr
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    let acr = acr.filter(|acr| !acr.is_empty());
+    let reauthenticated = current
+        .filter(|(_, session)| session.sub == sub)
+        .and_then(|(id, _)| {
+            session::reauthenticate(&app_state, &id, name.clone(), acr.clone(), amr.clone())
+                .map(|session| (session, session::cookie(&app_state, id)))
+        });
+    let (session, cookie) = match reauthenticated {
+        Some(reauthenticated) => reauthenticated,
+        None => {
+            let (_, session, cookie) = session::start(&app_state, sub, name, acr, amr);
+            (session, cookie)
         }
-    );
-    let id_token = crate::token::create_jwt(signing_secret, id_claims);
+    };
+
+    let mut response = if request.needs_consent(&app_state, &session) {
+        consent_page(&request)
+    } else {
+        authorize(&app_state, &request, response_mode, &session)
+    };
+    response.add_cookie(&cookie)?;
+    Ok(response)
+}
 
-    Ok(HttpResponse::build(StatusCode::SEE_OTHER)
-        .insert_header(("Location", format!("{redirect_uri}#access_token={access_token}&expires_in=86399&id_token={id_token}&state={state}&token_type=bearer", redirect_uri = form.redirect_uri, access_token = access_token, id_token = id_token, state = form.state)))
-        .finish()
+/// Loads the front-channel logout of the clients of the previous user, and then posts the login again.
+fn switch_user_page(form: &LoginParameters, frontchannel_logouts: &[String]) -> HttpResponse {
+    let login_fields: String = [
+        ("sub", Some(&form.sub)),
+        ("name", Some(&form.name)),
+        ("acr", form.acr.as_ref()),
+        ("amr", form.amr.as_ref()),
+    ]
+    .iter()
+    .filter_map(|(name, value)| value.map(|value| hidden_field(name, value)))
+    .collect();
+    page(format!(
+        include_str!("../template/switch_user.html"),
+        hidden_fields = hidden_fields(&form.request) + &login_fields,
+        frontchannel_logouts = logout::frontchannel_iframes(frontchannel_logouts)
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ConsentParameters {
+    #[serde(flatten)]
+    request: AuthParameters,
+    action: String,
+}
+
+/// Completes the authorization when the user of the session allows the client access.
+pub async fn consent(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<ConsentParameters>,
+) -> Result<HttpResponse, Error> {
+    let ConsentParameters { request, action } = form.into_inner();
+    let response_mode = request
+        .response_mode()
+        .unwrap_or_else(|_| ResponseMode::default_for(&request.response_type));
+    Ok(
+        match (session::current(&app_state, &req), action.as_str()) {
+            (Some((id, session)), "approve") => {
+                session::consent(&app_state, &id, &request.client_id);
+                authorize(&app_state, &request, response_mode, &session)
+            }
+            (Some(_), _) => error_response(
+                &app_state,
+                &request,
+                response_mode,
+                OAuthError::new("access_denied", "the user denied access"),
+            ),
+            (None, _) => error_response(
+                &app_state,
+                &request,
+                response_mode,
+                OAuthError::new("login_required", "the session has ended"),
+            ),
+        },
+    )
+}
+
+/// Returns the authorization response for the user of the session.
+fn authorize(
+    app_state: &AppState,
+    request: &AuthParameters,
+    response_mode: ResponseMode,
+    session: &Session,
+) -> HttpResponse {
+    let authorization = Authorization {
+        client_id: request.client_id.clone(),
+        sub: session.sub.clone(),
+        name: session.name.clone(),
+        scope: request.scope.clone(),
+        nonce: request.nonce.clone().filter(|nonce| !nonce.is_empty()),
+        auth_time: Some(session.auth_time),
+        acr: session.acr.clone(),
+        amr: session.amr.clone(),
+        claims: request.claims_request().unwrap_or_default(),
+        sid: Some(session.sid.clone()),
+    };
+    session::join(app_state, &session.sid, &request.client_id);
+
+    // Every part of the response_type adds its own values to the response, see OIDC Core section 3.3.2.5.
+    let code = request.returns("code").then(|| {
+        let code = nanoid::nanoid!();
+        let now = token::now();
+        let mut codes = app_state.codes.lock().unwrap();
+        codes.retain(|_, pending| pending.expires_at >= now);
+        codes.insert(
+            code.clone(),
+            AuthorizationCode {
+                authorization: authorization.clone(),
+                redirect_uri: request.redirect_uri.clone(),
+                code_challenge: request.code_challenge.clone().filter(|c| !c.is_empty()),
+                code_challenge_method: request
+                    .code_challenge_method
+                    .clone()
+                    .filter(|m| !m.is_empty()),
+                expires_at: now + CODE_LIFETIME,
+            },
+        );
+        code
+    });
+    let access_token = request
+        .returns("token")
+        .then(|| token::create_access_token(app_state, &authorization));
+    let id_token = request.returns("id_token").then(|| {
+        token::create_id_token(
+            app_state,
+            &authorization,
+            access_token.as_deref(),
+            code.as_deref(),
+        )
+    });
+
+    let expires_in = token::TOKEN_LIFETIME.to_string();
+    let mut params = Vec::new();
+    if let Some(code) = &code {
+        params.push(("code", code.as_str()));
+    }
+    if let Some(access_token) = &access_token {
+        params.push(("access_token", access_token.as_str()));
+        params.push(("expires_in", expires_in.as_str()));
+        params.push(("token_type", "bearer"));
+    }
+    if let Some(id_token) = &id_token {
+        params.push(("id_token", id_token.as_str()));
+    }
+    let session_state = session::session_state(session, &request.client_id, &request.redirect_uri);
+    params.push(("session_state", session_state.as_str()));
+    params.push(("state", request.state.as_str()));
+
+    response::respond(
+        app_state,
+        &request.client_id,
+        &request.redirect_uri,
+        response_mode,
+        &params,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Client, Config};
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+    use biscuit::{Empty, JWT};
+
+    #[actix_rt::test]
+    async fn test_route_login_code() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(login))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?code="));
+        assert!(location.ends_with("&state=a-state"));
+
+        let codes = state.codes.lock().unwrap();
+        let pending = codes.values().next().unwrap();
+        assert_eq!(pending.authorization.client_id, "fakeidp");
+        assert_eq!(pending.authorization.nonce, None);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_session_state() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(login))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        // The session_state is the hash the check_session_iframe calculates, with the salt after the dot.
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let (_, query) = location.split_once('?').unwrap();
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap();
+        let (_, session_state) = params
+            .iter()
+            .find(|(key, _)| key == "session_state")
+            .unwrap();
+        let (hash, salt) = session_state.split_once('.').unwrap();
+        let sid = state
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap()
+            .sid
+            .clone();
+        let expected = format!("fakeidp http://localhost:3000 {} {}", sid, salt);
+        let digest = ring::digest::digest(&ring::digest::SHA256, expected.as_bytes());
+        assert_eq!(hash, data_encoding::HEXLOWER.encode(digest.as_ref()));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_unsupported_response_mode() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(web::resource("/").route(web::get().to(auth))),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/?client_id=fakeidp&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=id_token%20token&scope=openid&state=a-state&nonce=a-nonce&response_mode=query")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+
+        // Errors for a response_type with tokens are returned in the fragment.
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback#error=invalid_request&"));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_hybrid() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(login))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code id_token"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("nonce", "a-nonce"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let (_, fragment) = location.split_once('#').unwrap();
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(fragment).unwrap();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(param("access_token"), None);
+        let code = param("code").unwrap();
+        let id_token = JWT::<Value, Empty>::new_encoded(&param("id_token").unwrap());
+        let claims = serde_json::to_value(id_token.unverified_payload().unwrap()).unwrap();
+        assert_eq!(claims["c_hash"], token::left_hash(&code));
+        assert_eq!(claims["nonce"], "a-nonce");
+        assert!(claims.get("at_hash").is_none());
+        // The name is released by the profile scope only.
+        assert!(claims.get("name").is_none());
+        let sid = claims["sid"].as_str().unwrap();
+        let sessions = state.sessions.lock().unwrap();
+        let session = sessions.values().next().unwrap();
+        assert_eq!(session.sid, sid);
+        assert_eq!(session.clients, vec!["fakeidp".to_string()]);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_jarm() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(login))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("response_mode", "jwt"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+
+        // The code flow defaults to query.jwt, with the response as only parameter.
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let (_, query) = location.split_once("?response=").unwrap();
+        let jwks = crate::discovery::create_jwk_set(state.rsa_key_pair.clone());
+        let claims = token::verify_jwt(&jwks, query).unwrap();
+        assert_eq!(claims["iss"], "http://localhost:8080");
+        assert_eq!(claims["aud"], "fakeidp");
+        assert_eq!(claims["state"], "a-state");
+        assert!(claims["code"].is_string());
+        assert!(!token::is_expired(&claims));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_prompt_none() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/auth/login").route(web::post().to(login)))
+                .service(web::resource("/auth").route(web::get().to(auth))),
+        )
+        .await;
+        let uri = "/auth?client_id=fakeidp&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=code&scope=openid&state=a-state&prompt=none";
+
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?error=login_required&"));
+
+        let req = test::TestRequest::post()
+            .uri("/auth/login")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(cookie.name(), session::SESSION_COOKIE);
+
+        // With the session cookie the code is returned without showing the login page.
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?code="));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_single_sign_on() -> Result<(), Error> {
+        for idle_timeout in [1800, 0] {
+            let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+                .expect("Cannot read RSA keypair");
+            let exposed_host = "http://localhost:8080".to_string();
+            let config = Config {
+                session_idle_timeout: idle_timeout,
+                ..Config::default()
+            };
+            let state = web::Data::new(AppState::with_config(rsa_keys, exposed_host, config));
+            let app = test::init_service(
+                App::new()
+                    .app_data(state.clone())
+                    .service(web::resource("/auth/login").route(web::post().to(login)))
+                    .service(web::resource("/auth").route(web::get().to(auth))),
+            )
+            .await;
+            let uri = "/auth?client_id=webshop&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=code&scope=openid&state=a-state";
+
+            let req = test::TestRequest::post()
+                .uri("/auth/login")
+                .set_form([
+                    ("client_id", "fakeidp"),
+                    ("redirect_uri", "http://localhost:3000/callback"),
+                    ("response_type", "code"),
+                    ("scope", "openid"),
+                    ("state", "a-state"),
+                    ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                    ("name", "Arie Ministrone"),
+                ])
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let cookie = resp.response().cookies().next().unwrap().into_owned();
+
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            if idle_timeout == 0 {
+                // The idle session has ended, so the user logs in again.
+                assert_eq!(resp.status(), http::StatusCode::OK);
+                logout::end_expired_sessions(&state).await;
+                assert!(state.sessions.lock().unwrap().is_empty());
+                continue;
+            }
+            // Another client gets a code for the user of the session without the login page.
+            assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+            let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+            assert!(location.starts_with("http://localhost:3000/callback?code="));
+
+            let req = test::TestRequest::get()
+                .uri(&format!("{}&prompt=login", uri))
+                .cookie(cookie)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_switches_user() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            clients: vec![Client {
+                client_id: "webshop".to_string(),
+                frontchannel_logout_uri: Some("http://localhost:3000/logout".to_string()),
+                // Nothing listens here, the delivery is only recorded.
+                backchannel_logout_uri: Some("http://127.0.0.1:1/logout".to_string()),
+                ..Client::default()
+            }],
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::with_config(rsa_keys, exposed_host, config));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/auth/login").route(web::post().to(login))),
+        )
+        .await;
+        let login_request = |sub: &str, acr: &str| {
+            test::TestRequest::post().uri("/auth/login").set_form([
+                ("client_id", "webshop"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("sub", sub),
+                ("name", sub),
+                ("acr", acr),
+            ])
+        };
+        let only_session = |state: &AppState| {
+            let sessions = state.sessions.lock().unwrap();
+            assert_eq!(sessions.len(), 1);
+            sessions.values().next().unwrap().clone()
+        };
+
+        let resp = test::call_service(&app, login_request("arie", "").to_request()).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let first = only_session(&state);
+
+        // The same user logging in again, like for a step-up of the acr, keeps the session.
+        let req = login_request("arie", "urn:mace:incommon:iap:silver")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+        let session = only_session(&state);
+        assert_eq!(session.sid, first.sid);
+        assert_eq!(session.acr.as_deref(), Some("urn:mace:incommon:iap:silver"));
+
+        // Another user ends the session, the page loads the front-channel logout and posts the login again.
+        let req = login_request("bert", "")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(&format!(
+            "<iframe src=\"http://localhost:3000/logout?iss=http%3A%2F%2Flocalhost%3A8080&amp;sid={}\"",
+            first.sid
+        )));
+        assert!(body.contains("<input type=\"hidden\" name=\"sub\" value=\"bert\" />"));
+        assert!(state.sessions.lock().unwrap().is_empty());
+        // The logout token is delivered in the background.
+        while state.logout_deliveries.lock().unwrap().is_empty() {
+            actix_rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let claims = token::verify_issued_jwt(
+            &state,
+            &state.logout_deliveries.lock().unwrap()[0].logout_token,
+        )
+        .unwrap();
+        assert_eq!(claims["sid"], first.sid.as_str());
+        assert_eq!(claims["sub"], "arie");
+
+        let req = login_request("bert", "").cookie(cookie).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+        let session = only_session(&state);
+        assert_eq!(session.sub, "bert");
+        assert_ne!(session.sid, first.sid);
+        assert_eq!(state.logout_deliveries.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_prompt_consent() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/auth/login").route(web::post().to(login)))
+                .service(web::resource("/auth/consent").route(web::post().to(consent))),
+        )
+        .await;
+        let request = [
+            ("client_id", "fakeidp"),
+            ("redirect_uri", "http://localhost:3000/callback"),
+            ("response_type", "code"),
+            ("scope", "openid profile"),
+            ("state", "a-state"),
+            ("prompt", "consent"),
+        ];
+
+        let mut form = request.to_vec();
+        form.push(("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"));
+        form.push(("name", "Arie Ministrone"));
+        let req = test::TestRequest::post()
+            .uri("/auth/login")
+            .set_form(form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        assert!(body.contains("<li>profile</li>"));
+
+        let mut form = request.to_vec();
+        form.push(("action", "deny"));
+        let req = test::TestRequest::post()
+            .uri("/auth/consent")
+            .cookie(cookie)
+            .set_form(form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?error=access_denied&"));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_login_acr_amr() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/auth/login").route(web::post().to(login)))
+                .service(web::resource("/auth").route(web::get().to(auth))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth/login")
+            .set_form([
+                ("client_id", "fakeidp"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "id_token"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+                ("nonce", "a-nonce"),
+                ("acr_values", "silver"),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+                ("acr", "silver"),
+                ("amr", "pwd otp"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let (_, fragment) = location.split_once('#').unwrap();
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(fragment).unwrap();
+        let id_token = JWT::<Value, Empty>::new_encoded(&params[0].1);
+        let claims = serde_json::to_value(id_token.unverified_payload().unwrap()).unwrap();
+        assert!(claims["auth_time"].is_u64());
+        assert_eq!(claims["acr"], "silver");
+        assert_eq!(claims["amr"], serde_json::json!(["pwd", "otp"]));
+
+        // The session is not strong enough for gold, so a silent login is not possible.
+        let req = test::TestRequest::get()
+            .uri("/auth?client_id=fakeidp&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=code&scope=openid&state=a-state&prompt=none&acr_values=gold")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?error=login_required&"));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_hints() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::get().to(auth))),
+        )
+        .await;
+        let uri = "/?client_id=fakeidp&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=code&scope=openid&state=a-state";
+
+        let id_token = token::create_id_token(
+            &state,
+            &Authorization {
+                client_id: "fakeidp".to_string(),
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                name: "Arie Ministrone".to_string(),
+                scope: "openid profile".to_string(),
+                ..Authorization::default()
+            },
+            None,
+            None,
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!("{}&id_token_hint={}", uri, id_token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        assert!(body.contains("value=\"F82E617D-DEAF-4EE6-8F96-CF3409060CA2\""));
+        assert!(body.contains("value=\"Arie Ministrone\""));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}&id_token_hint=not-a-token", uri))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?error=invalid_request&"));
+
+        Ok(())
+    }
+}
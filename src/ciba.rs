@@ -0,0 +1,465 @@
+use crate::errors::OAuthError;
+use crate::token::{self, Authorization, ClientCredentials, TokenParameters, TokenResponse};
+use crate::AppState;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Lifetime in seconds of a backchannel authentication request, unless the client asks for another requested_expiry.
+pub const CIBA_LIFETIME: u64 = 600;
+/// Longest lifetime in seconds a client can get with requested_expiry.
+pub const MAX_CIBA_LIFETIME: u64 = 3600;
+/// Minimum number of seconds a client in poll mode has to wait between polling the token endpoint.
+pub const POLLING_INTERVAL: u64 = 5;
+/// Seconds an expired request is kept, so polling its auth_req_id still gives expired_token.
+const EXPIRED_RETENTION: u64 = 600;
+
+/// A backchannel authentication request that waits for the tester to approve or deny it.
+pub struct BackchannelAuthentication {
+    pub client_id: String,
+    pub sub: String,
+    pub name: String,
+    pub scope: String,
+    pub binding_message: Option<String>,
+    pub client_notification_token: Option<String>,
+    pub expires_at: u64,
+    pub interval: u64,
+    pub last_poll: Option<u64>,
+    pub status: AuthenticationStatus,
+}
+
+pub enum AuthenticationStatus {
+    Pending,
+    Approved(Box<Authorization>),
+    Denied,
+}
+
+/// Form encoded parameters of a backchannel authentication request (CIBA Core section 7.1).
+#[derive(Deserialize)]
+pub struct BackchannelAuthenticationParameters {
+    #[serde(flatten)]
+    client: ClientCredentials,
+    scope: Option<String>,
+    login_hint: Option<String>,
+    id_token_hint: Option<String>,
+    binding_message: Option<String>,
+    client_notification_token: Option<String>,
+    requested_expiry: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackchannelAuthenticationResponse {
+    auth_req_id: String,
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+}
+
+/// The backchannel authentication endpoint of OpenID Connect CIBA Core section 7.
+pub async fn backchannel_authentication(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<BackchannelAuthenticationParameters>,
+) -> Result<HttpResponse, Error> {
+    let client_id = token::authenticate_client(&state, &req, &form.client)?;
+    let scope = form.scope.clone().unwrap_or_default();
+    if !token::has_scope(&scope, "openid") {
+        return Err(OAuthError::new("invalid_scope", "scope has to contain openid").into());
+    }
+    let (sub, name) = match (&form.login_hint, &form.id_token_hint) {
+        (Some(login_hint), None) => match state.config.user(login_hint) {
+            Some(user) => (user.sub.clone(), user.name.clone()),
+            // Any hint is accepted as subject, so tests do not have to register their users.
+            None => (login_hint.clone(), login_hint.clone()),
+        },
+        (None, Some(id_token_hint)) => {
+            let claims = token::verify_issued_jwt(&state, id_token_hint)
+                .ok_or_else(|| OAuthError::invalid_request("id_token_hint is not issued by us"))?;
+            let sub = claims["sub"]
+                .as_str()
+                .ok_or_else(|| OAuthError::invalid_request("id_token_hint has no sub claim"))?;
+            let name = match claims["name"].as_str() {
+                Some(name) => name.to_string(),
+                None => crate::auth::hinted_name(&state, sub),
+            };
+            (sub.to_string(), name)
+        }
+        _ => {
+            return Err(OAuthError::invalid_request(
+                "exactly one of login_hint and id_token_hint is required",
+            )
+            .into())
+        }
+    };
+    let ping = delivery_mode(&state, &client_id) == "ping";
+    if ping && form.client_notification_token.is_none() {
+        return Err(OAuthError::invalid_request(
+            "client_notification_token is required in ping mode",
+        )
+        .into());
+    }
+    let expires_in = match &form.requested_expiry {
+        Some(requested_expiry) => requested_expiry
+            .parse::<u64>()
+            .map_err(|_| OAuthError::invalid_request("requested_expiry is not a number"))?
+            .min(MAX_CIBA_LIFETIME),
+        None => CIBA_LIFETIME,
+    };
+
+    let auth_req_id = nanoid::nanoid!(32);
+    let now = token::now();
+    let mut authentications = state.backchannel_authentications.lock().unwrap();
+    authentications.retain(|_, pending| pending.expires_at + EXPIRED_RETENTION >= now);
+    authentications.insert(
+        auth_req_id.clone(),
+        BackchannelAuthentication {
+            client_id,
+            sub,
+            name,
+            scope,
+            binding_message: form.binding_message.clone(),
+            client_notification_token: form.client_notification_token.clone(),
+            expires_at: now + expires_in,
+            interval: POLLING_INTERVAL,
+            last_poll: None,
+            status: AuthenticationStatus::Pending,
+        },
+    );
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(BackchannelAuthenticationResponse {
+            auth_req_id,
+            expires_in,
+            interval: (!ping).then_some(POLLING_INTERVAL),
+        }))
+}
+
+/// The token delivery mode the client is registered with, poll when it did not register one.
+fn delivery_mode<'a>(state: &'a AppState, client_id: &str) -> &'a str {
+    state
+        .config
+        .client(client_id)
+        .and_then(|client| client.backchannel_token_delivery_mode.as_deref())
+        .unwrap_or("poll")
+}
+
+/// Lists the pending backchannel authentication requests, for a tester or test script to decide on.
+pub async fn pending(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let now = token::now();
+    let authentications = state.backchannel_authentications.lock().unwrap();
+    let pending: Vec<_> = authentications
+        .iter()
+        .filter(|(_, pending)| {
+            pending.expires_at >= now && matches!(pending.status, AuthenticationStatus::Pending)
+        })
+        .map(|(auth_req_id, pending)| {
+            json!({
+                "auth_req_id": auth_req_id,
+                "client_id": pending.client_id,
+                "sub": pending.sub,
+                "name": pending.name,
+                "scope": pending.scope,
+                "binding_message": pending.binding_message,
+                "expires_in": pending.expires_at - now,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(pending))
+}
+
+/// Approves or denies a pending backchannel authentication request.
+/// A client in ping mode is notified of the decision at its backchannel_client_notification_endpoint.
+pub async fn decide(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (auth_req_id, action) = path.into_inner();
+    let (client_id, client_notification_token) = {
+        let mut authentications = state.backchannel_authentications.lock().unwrap();
+        let pending = authentications
+            .get_mut(&auth_req_id)
+            .filter(|pending| matches!(pending.status, AuthenticationStatus::Pending))
+            .ok_or_else(|| OAuthError::invalid_request("auth_req_id is unknown or decided"))?;
+        if pending.expires_at < token::now() {
+            return Err(OAuthError::new("expired_token", "auth_req_id is expired").into());
+        }
+        pending.status = match action.as_str() {
+            "approve" => AuthenticationStatus::Approved(Box::new(Authorization {
+                client_id: pending.client_id.clone(),
+                sub: pending.sub.clone(),
+                name: pending.name.clone(),
+                scope: pending.scope.clone(),
+                auth_time: Some(token::now()),
+                ..Authorization::default()
+            })),
+            "deny" => AuthenticationStatus::Denied,
+            _ => return Err(OAuthError::invalid_request("action is approve or deny").into()),
+        };
+        (
+            pending.client_id.clone(),
+            pending.client_notification_token.clone(),
+        )
+    };
+
+    let mut result = json!({ "auth_req_id": auth_req_id, "status": action });
+    if delivery_mode(&state, &client_id) == "ping" {
+        let notification = notify(
+            &state,
+            &client_id,
+            &auth_req_id,
+            client_notification_token.as_deref().unwrap_or_default(),
+        )
+        .await;
+        result["notification"] = json!(notification.err().unwrap_or("delivered".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Pings the client that the result of the authentication can be fetched, see CIBA Core section 10.2.
+async fn notify(
+    state: &AppState,
+    client_id: &str,
+    auth_req_id: &str,
+    client_notification_token: &str,
+) -> Result<(), String> {
+    let endpoint = state
+        .config
+        .client(client_id)
+        .and_then(|client| client.backchannel_client_notification_endpoint.clone())
+        .ok_or_else(|| "the client has no backchannel_client_notification_endpoint".to_string())?;
+    state
+        .http_client
+        .post(endpoint)
+        .bearer_auth(client_notification_token)
+        .json(&json!({ "auth_req_id": auth_req_id }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// The CIBA grant of CIBA Core section 10.1.
+pub fn ciba_grant(
+    state: &AppState,
+    req: &HttpRequest,
+    params: TokenParameters,
+) -> Result<TokenResponse, OAuthError> {
+    let client_id = token::authenticate_client(state, req, &params.client)?;
+    let auth_req_id = params
+        .auth_req_id
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("auth_req_id is missing"))?;
+    let ping = delivery_mode(state, &client_id) == "ping";
+    let now = token::now();
+
+    let mut authentications = state.backchannel_authentications.lock().unwrap();
+    let pending = authentications
+        .get_mut(auth_req_id)
+        .filter(|pending| pending.client_id == client_id)
+        .ok_or_else(|| OAuthError::invalid_grant("auth_req_id is unknown"))?;
+
+    if pending.expires_at < now {
+        authentications.remove(auth_req_id);
+        return Err(OAuthError::new("expired_token", "auth_req_id is expired"));
+    }
+    match &pending.status {
+        AuthenticationStatus::Pending => {
+            let too_fast = !ping
+                && pending
+                    .last_poll
+                    .is_some_and(|last_poll| now < last_poll + pending.interval);
+            pending.last_poll = Some(now);
+            if too_fast {
+                pending.interval += POLLING_INTERVAL;
+                Err(OAuthError::new("slow_down", "polling too fast"))
+            } else {
+                Err(OAuthError::new(
+                    "authorization_pending",
+                    "the user has not yet been authenticated",
+                ))
+            }
+        }
+        AuthenticationStatus::Denied => {
+            authentications.remove(auth_req_id);
+            Err(OAuthError::new("access_denied", "the user denied access"))
+        }
+        AuthenticationStatus::Approved(authorization) => {
+            let authorization = authorization.as_ref().clone();
+            authentications.remove(auth_req_id);
+            drop(authentications);
+            let refresh_token = crate::refresh::issue(state, &authorization);
+            Ok(token::token_response(state, &authorization, refresh_token))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Client, Config, User};
+    use crate::token::create_token;
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+    use serde_json::Value;
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            clients: vec![Client {
+                client_id: "call-centre".to_string(),
+                backchannel_token_delivery_mode: Some("ping".to_string()),
+                backchannel_client_notification_endpoint: Some(
+                    "http://localhost:3000/ciba".to_string(),
+                ),
+                ..Client::default()
+            }],
+            users: vec![User {
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                username: "arie".to_string(),
+                name: "Arie Ministrone".to_string(),
+                ..User::default()
+            }],
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    fn create_poll_request(auth_req_id: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/token")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(format!(
+                "grant_type=urn%3Aopenid%3Aparams%3Agrant-type%3Aciba&client_id=kiosk&auth_req_id={}",
+                auth_req_id
+            ))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_ciba_poll() -> Result<(), Error> {
+        let app = test::init_service(
+            App::new()
+                .app_data(create_state())
+                .service(
+                    web::resource("/bc-authorize")
+                        .route(web::post().to(backchannel_authentication)),
+                )
+                .service(web::resource("/admin/ciba").route(web::get().to(pending)))
+                .service(
+                    web::resource("/admin/ciba/{auth_req_id}/{action}")
+                        .route(web::post().to(decide)),
+                )
+                .service(web::resource("/token").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/bc-authorize")
+            .set_form([
+                ("client_id", "kiosk"),
+                ("scope", "openid"),
+                ("login_hint", "arie"),
+                ("binding_message", "W4SCT"),
+                ("requested_expiry", "18446744073709551615"),
+            ])
+            .to_request();
+        let p: Value = test::call_and_read_body_json(&app, req).await;
+        let auth_req_id = p["auth_req_id"].as_str().unwrap().to_string();
+        assert_eq!(p["interval"], 5);
+        assert_eq!(p["expires_in"], MAX_CIBA_LIFETIME);
+
+        let resp = test::call_service(&app, create_poll_request(&auth_req_id).to_request()).await;
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "authorization_pending");
+
+        let req = test::TestRequest::get().uri("/admin/ciba").to_request();
+        let p: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(p[0]["binding_message"], "W4SCT");
+        assert_eq!(p[0]["sub"], "F82E617D-DEAF-4EE6-8F96-CF3409060CA2");
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/ciba/{}/approve", auth_req_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // Once decided, the result is returned regardless of the polling interval.
+        let resp = test::call_service(&app, create_poll_request(&auth_req_id).to_request()).await;
+        let p: Value = test::read_body_json(resp).await;
+        assert!(p["access_token"].is_string());
+        assert!(p["id_token"].is_string());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_ciba_expired() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(
+                    web::resource("/bc-authorize")
+                        .route(web::post().to(backchannel_authentication)),
+                )
+                .service(web::resource("/token").route(web::post().to(create_token))),
+        )
+        .await;
+        let authentication_request = || {
+            test::TestRequest::post()
+                .uri("/bc-authorize")
+                .set_form([
+                    ("client_id", "kiosk"),
+                    ("scope", "openid"),
+                    ("login_hint", "arie"),
+                ])
+                .to_request()
+        };
+
+        let p: Value = test::call_and_read_body_json(&app, authentication_request()).await;
+        let auth_req_id = p["auth_req_id"].as_str().unwrap().to_string();
+        // Pretend the request expired a minute ago.
+        state
+            .backchannel_authentications
+            .lock()
+            .unwrap()
+            .get_mut(&auth_req_id)
+            .unwrap()
+            .expires_at = token::now() - 60;
+
+        // Another request does not make the expired auth_req_id unknown.
+        let resp = test::call_service(&app, authentication_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = test::call_service(&app, create_poll_request(&auth_req_id).to_request()).await;
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "expired_token");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_ciba_ping_requires_notification_token() -> Result<(), Error> {
+        let app = test::init_service(App::new().app_data(create_state()).service(
+            web::resource("/bc-authorize").route(web::post().to(backchannel_authentication)),
+        ))
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/bc-authorize")
+            .set_form([
+                ("client_id", "call-centre"),
+                ("scope", "openid"),
+                ("login_hint", "arie"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_request");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use crate::token::{self, Authorization};
+use crate::AppState;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// The claims request parameter of OIDC Core section 5.5, with the claims requested for the ID token and userinfo.
+/// Every requested claim is null or an object with `essential`, `value` or `values`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ClaimsRequest {
+    #[serde(default)]
+    pub userinfo: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub id_token: Option<Map<String, Value>>,
+}
+
+impl ClaimsRequest {
+    pub fn parse(claims: &str) -> Result<Self, String> {
+        serde_json::from_str(claims).map_err(|err| format!("claims is not valid: {}", err))
+    }
+}
+
+/// The standard claims released by the scopes of OIDC Core section 5.4, with groups as an extra scope.
+pub const SCOPE_CLAIMS: [(&str, &[&str]); 5] = [
+    (
+        "profile",
+        &[
+            "name",
+            "family_name",
+            "given_name",
+            "middle_name",
+            "nickname",
+            "preferred_username",
+            "profile",
+            "picture",
+            "website",
+            "gender",
+            "birthdate",
+            "zoneinfo",
+            "locale",
+            "updated_at",
+        ],
+    ),
+    ("email", &["email", "email_verified"]),
+    ("address", &["address"]),
+    ("phone", &["phone_number", "phone_number_verified"]),
+    ("groups", &["groups"]),
+];
+
+/// All claims fakeidp knows of the user that logged in.
+/// The name comes from the login, the other claims from the registered user with the same sub.
+pub fn user_claims(state: &AppState, authorization: &Authorization) -> Map<String, Value> {
+    let mut claims = Map::new();
+    if let Some(user) = state.config.user_by_sub(&authorization.sub) {
+        claims.extend(user.claims.clone());
+        claims.insert("preferred_username".to_string(), json!(user.username));
+    }
+    claims.insert("name".to_string(), json!(authorization.name));
+    claims
+}
+
+/// The claims of the user that are released by the granted scopes.
+pub fn scope_claims(state: &AppState, authorization: &Authorization) -> Map<String, Value> {
+    let mut claims = user_claims(state, authorization);
+    claims.retain(|name, _| {
+        SCOPE_CLAIMS.iter().any(|(scope, names)| {
+            token::has_scope(&authorization.scope, scope) && names.contains(&name.as_str())
+        })
+    });
+    claims
+}
+
+/// Picks the requested claims from the claims of the user.
+/// A claim the user has no value for gets the requested value, so a tester can ask for any value.
+pub fn requested(
+    available: &Map<String, Value>,
+    requested: &Map<String, Value>,
+) -> Map<String, Value> {
+    requested
+        .iter()
+        .filter_map(|(name, request)| {
+            available
+                .get(name)
+                .or_else(|| request.get("value"))
+                .or_else(|| request.get("values").and_then(|values| values.get(0)))
+                .map(|value| (name.clone(), value.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, User};
+    use biscuit::jws::Secret;
+
+    fn create_state() -> AppState {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let claims = json!({
+            "email": "arie@example.com",
+            "email_verified": true,
+            "phone_number": "+31 20 123 4567",
+            "groups": ["testers"]
+        });
+        let config = Config {
+            users: vec![User {
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                username: "arie".to_string(),
+                name: "Arie Ministrone".to_string(),
+                claims: claims.as_object().unwrap().clone(),
+                ..User::default()
+            }],
+            ..Config::default()
+        };
+        AppState::with_config(rsa_keys, exposed_host, config)
+    }
+
+    fn create_authorization(scope: &str) -> Authorization {
+        Authorization {
+            sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            name: "Arie Ministrone".to_string(),
+            scope: scope.to_string(),
+            ..Authorization::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_claims() {
+        let state = create_state();
+        let claims = scope_claims(&state, &create_authorization("openid email groups"));
+        assert_eq!(claims["email"], "arie@example.com");
+        assert_eq!(claims["email_verified"], true);
+        assert_eq!(claims["groups"], json!(["testers"]));
+        assert!(!claims.contains_key("phone_number"));
+        assert!(!claims.contains_key("preferred_username"));
+
+        let claims = scope_claims(&state, &create_authorization("openid profile"));
+        assert_eq!(claims["preferred_username"], "arie");
+        assert_eq!(claims["name"], "Arie Ministrone");
+        assert!(!claims.contains_key("email"));
+    }
+
+    #[actix_rt::test]
+    async fn test_requested_claims() {
+        let request = ClaimsRequest::parse(
+            r#"{"id_token": {"name": {"essential": true}, "email": {"value": "arie@example.com"}, "phone_number": null, "locale": null}}"#,
+        )
+        .unwrap();
+        let state = create_state();
+        let claims = requested(
+            &user_claims(&state, &create_authorization("openid")),
+            request.id_token.as_ref().unwrap(),
+        );
+        assert_eq!(claims["name"], "Arie Ministrone");
+        assert_eq!(claims["email"], "arie@example.com");
+        assert_eq!(claims["phone_number"], "+31 20 123 4567");
+        assert!(!claims.contains_key("locale"));
+        assert!(request.userinfo.is_none());
+    }
+}
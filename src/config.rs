@@ -0,0 +1,126 @@
+use biscuit::jwk::JWKSet;
+use biscuit::Empty;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::fs;
+use std::io;
+
+/// Settings of the fake IdP itself.
+/// The scalar settings are given on the command line, the registrations are read from the JSON config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Issue a new refresh token on every use and revoke the whole family when a used one comes back.
+    #[serde(skip)]
+    pub refresh_token_rotation: bool,
+    /// Seconds a family of refresh tokens is valid after the first one was issued, regardless of use.
+    #[serde(skip)]
+    pub refresh_token_lifetime: u64,
+    /// Seconds a refresh token stays valid without being used.
+    #[serde(skip)]
+    pub refresh_token_idle_lifetime: u64,
+    /// Seconds a browser session at fakeidp lasts after the login, regardless of use.
+    #[serde(skip)]
+    pub session_lifetime: u64,
+    /// Seconds a browser session lasts without an authorization request.
+    #[serde(skip)]
+    pub session_idle_timeout: u64,
+    pub clients: Vec<Client>,
+    pub users: Vec<User>,
+    pub trusted_issuers: Vec<TrustedIssuer>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            refresh_token_rotation: false,
+            refresh_token_lifetime: 2592000,
+            refresh_token_idle_lifetime: 86400,
+            session_lifetime: 28800,
+            session_idle_timeout: 1800,
+            clients: Vec::new(),
+            users: Vec::new(),
+            trusted_issuers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content).map_err(io::Error::from)
+    }
+
+    pub fn client(&self, client_id: &str) -> Option<&Client> {
+        self.clients
+            .iter()
+            .find(|client| client.client_id == client_id)
+    }
+
+    pub fn user(&self, username: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.username == username)
+    }
+
+    pub fn user_by_sub(&self, sub: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.sub == sub)
+    }
+
+    pub fn trusted_issuer(&self, issuer: &str) -> Option<&TrustedIssuer> {
+        self.trusted_issuers
+            .iter()
+            .find(|trusted| trusted.issuer == issuer)
+    }
+}
+
+/// A registered client.
+/// Clients that are not registered can still use the flows that do not need client authentication.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Client {
+    pub client_id: String,
+    /// Secret for client_secret_basic and client_secret_post, a client without one is a public client.
+    pub client_secret: Option<String>,
+    /// Space separated scopes the client is allowed to request for itself with client_credentials.
+    pub scope: String,
+    /// Audience of the access tokens issued to the client itself, defaults to its client_id.
+    pub audience: Vec<String>,
+    /// Show the consent screen the first time the client is authorized in a session.
+    pub require_consent: bool,
+    /// Only accept authorization requests that were pushed to the /par endpoint first.
+    pub require_pushed_authorization_requests: bool,
+    /// Where the client may send the browser after it logged out at the end_session_endpoint.
+    pub post_logout_redirect_uris: Vec<String>,
+    /// Page that is loaded in an iframe with the iss and sid when the user logs out of fakeidp.
+    pub frontchannel_logout_uri: Option<String>,
+    /// Endpoint the logout token is posted to when the user logs out of fakeidp.
+    pub backchannel_logout_uri: Option<String>,
+    /// The public keys the client signs its request objects with.
+    pub jwks: Option<JWKSet<Empty>>,
+    /// How the client gets the result of a backchannel authentication, poll or ping. Defaults to poll.
+    pub backchannel_token_delivery_mode: Option<String>,
+    /// Endpoint that is pinged when a backchannel authentication of a client in ping mode is decided.
+    pub backchannel_client_notification_endpoint: Option<String>,
+}
+
+/// A registered user, to log in with the resource owner password credentials grant.
+/// A login with the sub of a registered user gets its claims.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct User {
+    pub sub: String,
+    pub username: String,
+    pub password: String,
+    pub name: String,
+    /// A disabled user is known, but cannot log in.
+    pub disabled: bool,
+    /// Standard claims of the user, like email, phone_number, address or groups, released by their scope.
+    pub claims: Map<String, Value>,
+}
+
+/// An issuer of JWT assertions that can be exchanged for an access token with the jwt-bearer grant.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrustedIssuer {
+    pub issuer: String,
+    /// The public keys the assertions of this issuer are signed with.
+    pub jwks: JWKSet<Empty>,
+}
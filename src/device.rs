@@ -0,0 +1,353 @@
+use crate::auth::escape_html;
+use crate::errors::OAuthError;
+use crate::token::{self, Authorization, ClientCredentials, TokenParameters, TokenResponse};
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use nanoid::nanoid;
+use serde_derive::{Deserialize, Serialize};
+
+/// Lifetime in seconds of a device_code and its user_code.
+pub const DEVICE_CODE_LIFETIME: u64 = 600;
+/// Minimum number of seconds the device has to wait between polling the token endpoint.
+pub const POLLING_INTERVAL: u64 = 5;
+/// Seconds an expired device_code is kept, so polling it still gives expired_token instead of an unknown code.
+const EXPIRED_RETENTION: u64 = 600;
+
+// Characters of the user_code, without vowels and look-alikes as recommended by RFC 8628 section 6.1.
+const USER_CODE_ALPHABET: [char; 20] = [
+    'B', 'C', 'D', 'F', 'G', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'V', 'W', 'X',
+    'Z',
+];
+
+/// A device authorization request that is waiting for the user to enter the user_code.
+pub struct DeviceAuthorization {
+    pub client_id: String,
+    pub scope: String,
+    pub user_code: String,
+    pub expires_at: u64,
+    pub interval: u64,
+    pub last_poll: Option<u64>,
+    pub status: DeviceStatus,
+}
+
+pub enum DeviceStatus {
+    Pending,
+    Approved(Box<Authorization>),
+    Denied,
+}
+
+/// Form encoded parameters of a device authorization request (RFC 8628 section 3.1).
+#[derive(Deserialize)]
+pub struct DeviceAuthorizationParameters {
+    #[serde(flatten)]
+    client: ClientCredentials,
+    scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+/// The device authorization endpoint of RFC 8628 section 3.1.
+pub async fn device_authorization(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<DeviceAuthorizationParameters>,
+) -> Result<HttpResponse, Error> {
+    let client_id = token::authenticate_client(&state, &req, &form.client)?;
+    let device_code = nanoid!(32);
+    let user_code = format!(
+        "{}-{}",
+        nanoid!(4, &USER_CODE_ALPHABET),
+        nanoid!(4, &USER_CODE_ALPHABET)
+    );
+    let now = token::now();
+
+    let mut device_codes = state.device_codes.lock().unwrap();
+    device_codes.retain(|_, pending| pending.expires_at + EXPIRED_RETENTION >= now);
+    device_codes.insert(
+        device_code.clone(),
+        DeviceAuthorization {
+            client_id,
+            scope: form.scope.clone().unwrap_or_else(|| "openid".to_string()),
+            user_code: user_code.clone(),
+            expires_at: now + DEVICE_CODE_LIFETIME,
+            interval: POLLING_INTERVAL,
+            last_poll: None,
+            status: DeviceStatus::Pending,
+        },
+    );
+
+    let verification_uri = format!("{}/device", state.exposed_host);
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(DeviceAuthorizationResponse {
+            device_code,
+            verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+            verification_uri,
+            user_code,
+            expires_in: DEVICE_CODE_LIFETIME,
+            interval: POLLING_INTERVAL,
+        }))
+}
+
+#[derive(Deserialize)]
+pub struct VerificationParameters {
+    user_code: Option<String>,
+}
+
+/// The verification page where the tester enters the user_code and picks the subject.
+pub async fn verification(info: web::Query<VerificationParameters>) -> Result<HttpResponse, Error> {
+    Ok(verification_page(
+        "Enter the code shown on your device.",
+        info.user_code.as_deref().unwrap_or_default(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ApprovalParameters {
+    user_code: String,
+    sub: Option<String>,
+    name: Option<String>,
+    action: String,
+}
+
+pub async fn approve(
+    state: web::Data<AppState>,
+    form: web::Form<ApprovalParameters>,
+) -> Result<HttpResponse, Error> {
+    let user_code = form.user_code.trim().to_uppercase();
+    let mut device_codes = state.device_codes.lock().unwrap();
+    let pending = device_codes.values_mut().find(|pending| {
+        pending.user_code == user_code && matches!(pending.status, DeviceStatus::Pending)
+    });
+
+    let message = match (pending, form.action.as_str()) {
+        (None, _) => "The code is unknown or already used.",
+        (Some(pending), _) if pending.expires_at < token::now() => "The code is expired.",
+        (Some(pending), "approve") => {
+            let sub = form.sub.clone().filter(|sub| !sub.is_empty());
+            match sub {
+                Some(sub) => {
+                    pending.status = DeviceStatus::Approved(Box::new(Authorization {
+                        client_id: pending.client_id.clone(),
+                        name: form.name.clone().unwrap_or_default(),
+                        sub,
+                        scope: pending.scope.clone(),
+                        auth_time: Some(token::now()),
+                        ..Authorization::default()
+                    }));
+                    "The device is connected, you can return to your device."
+                }
+                None => "A subject is required to approve the device.",
+            }
+        }
+        (Some(pending), _) => {
+            pending.status = DeviceStatus::Denied;
+            "The device is denied access."
+        }
+    };
+    Ok(verification_page(message, ""))
+}
+
+fn verification_page(message: &str, user_code: &str) -> HttpResponse {
+    let body = format!(
+        include_str!("../template/device.html"),
+        message = escape_html(message),
+        user_code = escape_html(user_code)
+    );
+    HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+/// The device_code grant of RFC 8628 section 3.4.
+pub fn device_code_grant(
+    state: &AppState,
+    req: &HttpRequest,
+    params: TokenParameters,
+) -> Result<TokenResponse, OAuthError> {
+    let client_id = token::authenticate_client(state, req, &params.client)?;
+    let device_code = params
+        .device_code
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("device_code is missing"))?;
+    let now = token::now();
+
+    let mut device_codes = state.device_codes.lock().unwrap();
+    let pending = device_codes
+        .get_mut(device_code)
+        .filter(|pending| pending.client_id == client_id)
+        .ok_or_else(|| OAuthError::invalid_grant("device_code is unknown"))?;
+
+    if pending.expires_at < now {
+        device_codes.remove(device_code);
+        return Err(OAuthError::new("expired_token", "device_code is expired"));
+    }
+    match &pending.status {
+        DeviceStatus::Pending => {
+            let too_fast = pending
+                .last_poll
+                .is_some_and(|last_poll| now < last_poll + pending.interval);
+            pending.last_poll = Some(now);
+            if too_fast {
+                // RFC 8628 section 3.5: the client has to add 5 seconds to its interval.
+                pending.interval += POLLING_INTERVAL;
+                Err(OAuthError::new("slow_down", "polling too fast"))
+            } else {
+                Err(OAuthError::new(
+                    "authorization_pending",
+                    "the user has not yet entered the user_code",
+                ))
+            }
+        }
+        DeviceStatus::Denied => {
+            device_codes.remove(device_code);
+            Err(OAuthError::new("access_denied", "the user denied access"))
+        }
+        DeviceStatus::Approved(authorization) => {
+            let authorization = authorization.as_ref().clone();
+            device_codes.remove(device_code);
+            drop(device_codes);
+            let refresh_token = crate::refresh::issue(state, &authorization);
+            Ok(token::token_response(state, &authorization, refresh_token))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::create_token;
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+    use serde_json::Value;
+
+    fn create_poll_request(device_code: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/token")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(format!(
+                "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code&client_id=tv&device_code={}",
+                device_code
+            ))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_device_flow() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(
+                    web::resource("/device_authorization")
+                        .route(web::post().to(device_authorization)),
+                )
+                .service(web::resource("/device").route(web::post().to(approve)))
+                .service(web::resource("/token").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/device_authorization")
+            .set_form([("client_id", "tv"), ("scope", "openid profile")])
+            .to_request();
+        let p: Value = test::call_and_read_body_json(&app, req).await;
+        let device_code = p["device_code"].as_str().unwrap().to_string();
+        let user_code = p["user_code"].as_str().unwrap().to_string();
+        assert_eq!(p["verification_uri"], "http://localhost:8080/device");
+        assert_eq!(p["interval"], 5);
+
+        let resp = test::call_service(&app, create_poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "authorization_pending");
+
+        let resp = test::call_service(&app, create_poll_request(&device_code).to_request()).await;
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "slow_down");
+
+        let req = test::TestRequest::post()
+            .uri("/device")
+            .set_form([
+                ("user_code", user_code.to_lowercase().as_str()),
+                ("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+                ("name", "Arie Ministrone"),
+                ("action", "approve"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // Pretend the device waited long enough before polling again.
+        state
+            .device_codes
+            .lock()
+            .unwrap()
+            .get_mut(&device_code)
+            .unwrap()
+            .last_poll = None;
+        let resp = test::call_service(&app, create_poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let p: Value = test::read_body_json(resp).await;
+        assert!(p["access_token"].is_string());
+        assert!(p["id_token"].is_string());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_device_code_expired() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let state = web::Data::new(AppState::new(rsa_keys, exposed_host));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(
+                    web::resource("/device_authorization")
+                        .route(web::post().to(device_authorization)),
+                )
+                .service(web::resource("/token").route(web::post().to(create_token))),
+        )
+        .await;
+        let device_authorization_request = || {
+            test::TestRequest::post()
+                .uri("/device_authorization")
+                .set_form([("client_id", "tv"), ("scope", "openid")])
+                .to_request()
+        };
+
+        let p: Value = test::call_and_read_body_json(&app, device_authorization_request()).await;
+        let device_code = p["device_code"].as_str().unwrap().to_string();
+        // Pretend the device_code expired a minute ago.
+        state
+            .device_codes
+            .lock()
+            .unwrap()
+            .get_mut(&device_code)
+            .unwrap()
+            .expires_at = token::now() - 60;
+
+        // Another device authorization does not make the expired device_code unknown.
+        let resp = test::call_service(&app, device_authorization_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = test::call_service(&app, create_poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "expired_token");
+
+        Ok(())
+    }
+}
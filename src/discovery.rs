@@ -46,16 +46,72 @@ pub fn create_jwk_set(secret: Secret) -> JWKSet<Empty> {
 }
 
 pub async fn openid_configuration(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let scope_claims = crate::claims::SCOPE_CLAIMS;
+    let scopes: Vec<&str> = std::iter::once("openid")
+        .chain(scope_claims.iter().map(|(scope, _)| *scope))
+        .chain(std::iter::once("offline_access"))
+        .collect();
+    let claims: Vec<&str> = [
+        "iss",
+        "sub",
+        "aud",
+        "exp",
+        "iat",
+        "auth_time",
+        "nonce",
+        "acr",
+        "amr",
+        "at_hash",
+        "c_hash",
+        "sid",
+    ]
+    .into_iter()
+    .chain(
+        scope_claims
+            .iter()
+            .flat_map(|(_, claims)| claims.iter().copied()),
+    )
+    .collect();
     let keys_response = json!( {
       "issuer": format!("{}", state.exposed_host),
       "authorization_endpoint": format!("{}/auth", state.exposed_host),
       "token_endpoint": format!("{}/token", state.exposed_host),
       "jwks_uri": format!("{}/keys", state.exposed_host),
+      "introspection_endpoint": format!("{}/introspect", state.exposed_host),
       "userinfo_endpoint": format!("{}/userinfo", state.exposed_host),
-      "response_types_supported": [
-        "code",
-        "id_token",
-        "token"
+      "end_session_endpoint": format!("{}/logout", state.exposed_host),
+      "check_session_iframe": format!("{}/check_session", state.exposed_host),
+      "frontchannel_logout_supported": true,
+      "frontchannel_logout_session_supported": true,
+      "backchannel_logout_supported": true,
+      "backchannel_logout_session_supported": true,
+      "device_authorization_endpoint": format!("{}/device_authorization", state.exposed_host),
+      "pushed_authorization_request_endpoint": format!("{}/par", state.exposed_host),
+      "backchannel_authentication_endpoint": format!("{}/bc-authorize", state.exposed_host),
+      "backchannel_token_delivery_modes_supported": [
+        "poll",
+        "ping"
+      ],
+      "backchannel_user_code_parameter_supported": false,
+      "require_pushed_authorization_requests": false,
+      "response_types_supported": crate::auth::RESPONSE_TYPES,
+      "response_modes_supported": crate::response::RESPONSE_MODES,
+      "grant_types_supported": [
+        "authorization_code",
+        "implicit",
+        "refresh_token",
+        "client_credentials",
+        "password",
+        "urn:ietf:params:oauth:grant-type:device_code",
+        "urn:ietf:params:oauth:grant-type:token-exchange",
+        "urn:ietf:params:oauth:grant-type:jwt-bearer",
+        "urn:openid:params:grant-type:ciba"
+      ],
+      "prompt_values_supported": [
+        "none",
+        "login",
+        "consent",
+        "select_account"
       ],
       "subject_types_supported": [
         "public"
@@ -63,27 +119,27 @@ pub async fn openid_configuration(state: web::Data<AppState>) -> Result<HttpResp
       "id_token_signing_alg_values_supported": [
         "RS256"
       ],
-      "scopes_supported": [
-        "openid",
-        "email",
-        "groups",
-        "profile",
-        "offline_access"
+      "scopes_supported": scopes,
+      "request_parameter_supported": true,
+      "request_uri_parameter_supported": true,
+      "require_request_uri_registration": false,
+      "request_object_signing_alg_values_supported": [
+        "RS256"
+      ],
+      "authorization_signing_alg_values_supported": [
+        "RS256"
       ],
+      "claims_parameter_supported": true,
+      "code_challenge_methods_supported": crate::token::PKCE_METHODS,
       "token_endpoint_auth_methods_supported": [
-        "client_secret_basic"
+        "client_secret_basic",
+        "client_secret_post"
       ],
-      "claims_supported": [
-        "aud",
-        "email",
-        "email_verified",
-        "exp",
-        "iat",
-        "iss",
-        "locale",
-        "name",
-        "sub"
-      ]
+      "introspection_endpoint_auth_methods_supported": [
+        "client_secret_basic",
+        "client_secret_post"
+      ],
+      "claims_supported": claims
     });
     Ok(HttpResponse::Ok().json(keys_response))
 }
@@ -113,10 +169,8 @@ mod tests {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), http::StatusCode::OK);
         let response_body = test::read_body(resp).await;
-        let body_str = match str::from_utf8(&response_body) {
-            Ok(v) => v,
-            Err(_e) => "Error with parsing result from bytes to string",
-        };
+        let body_str = str::from_utf8(&response_body)
+            .unwrap_or("Error with parsing result from bytes to string");
 
         let p: Value = serde_json::from_str(body_str).unwrap();
         println!("Value : {:?}", p);
@@ -0,0 +1,235 @@
+use crate::errors::OAuthError;
+use crate::token::{self, TokenParameters, TokenResponse};
+use crate::AppState;
+use actix_web::HttpRequest;
+use serde_json::{json, Value};
+
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// The token exchange grant of RFC 8693.
+/// Without an actor_token the new token impersonates the subject, with one the actor gets delegated access
+/// and is named in the act claim.
+pub fn token_exchange_grant(
+    state: &AppState,
+    req: &HttpRequest,
+    params: TokenParameters,
+) -> Result<TokenResponse, OAuthError> {
+    let client_id = token::authenticate_client(state, req, &params.client)?;
+    let subject = verify_token(
+        state,
+        "subject_token",
+        params.subject_token.as_deref(),
+        params.subject_token_type.as_deref(),
+    )?;
+    let actor = match &params.actor_token {
+        Some(actor_token) => Some(verify_token(
+            state,
+            "actor_token",
+            Some(actor_token),
+            params.actor_token_type.as_deref(),
+        )?),
+        None => None,
+    };
+    let issued_token_type = match params.requested_token_type.as_deref() {
+        None | Some(ACCESS_TOKEN_TYPE) => ACCESS_TOKEN_TYPE,
+        Some(JWT_TOKEN_TYPE) => JWT_TOKEN_TYPE,
+        Some(other) => {
+            return Err(OAuthError::invalid_request(format!(
+                "requested_token_type {} is not supported",
+                other
+            )))
+        }
+    };
+
+    let audience = params
+        .audience
+        .clone()
+        .or_else(|| params.resource.clone())
+        .unwrap_or_else(|| client_id.clone());
+    let scope = params
+        .scope
+        .clone()
+        .or_else(|| subject["scope"].as_str().map(String::from));
+
+    let iat = token::now();
+    let mut claims = json!(
+        {
+            "iss": state.exposed_host,
+            "sub": subject["sub"],
+            "aud": audience,
+            "client_id": client_id,
+            "iat": iat,
+            "exp": iat + token::TOKEN_LIFETIME
+        }
+    );
+    if let Some(name) = subject.get("name") {
+        claims["name"] = name.clone();
+    }
+    if let Some(scope) = &scope {
+        claims["scope"] = json!(scope);
+    }
+    if let Some(actor) = actor {
+        // A delegation chain is kept by nesting the act claim of the actor, see RFC 8693 section 4.1.
+        let mut act = json!({ "sub": actor["sub"] });
+        if let Some(prior) = actor.get("act") {
+            act["act"] = prior.clone();
+        }
+        claims["act"] = act;
+    }
+
+    Ok(TokenResponse {
+        access_token: match issued_token_type {
+            ACCESS_TOKEN_TYPE => token::create_access_token_jwt(&state.rsa_key_pair, claims),
+            _ => token::create_jwt(&state.rsa_key_pair, claims),
+        },
+        token_type: match issued_token_type {
+            ACCESS_TOKEN_TYPE => "Bearer",
+            _ => "N_A",
+        },
+        expires_in: token::TOKEN_LIFETIME,
+        id_token: None,
+        refresh_token: None,
+        scope,
+        issued_token_type: Some(issued_token_type),
+    })
+}
+
+/// Verifies a subject or actor token, which has to be an unexpired JWT issued by this fakeidp.
+fn verify_token(
+    state: &AppState,
+    name: &str,
+    token: Option<&str>,
+    token_type: Option<&str>,
+) -> Result<Value, OAuthError> {
+    let token = token.ok_or_else(|| OAuthError::invalid_request(format!("{} is missing", name)))?;
+    match token_type {
+        Some(ACCESS_TOKEN_TYPE | ID_TOKEN_TYPE | JWT_TOKEN_TYPE) => {}
+        Some(other) => {
+            return Err(OAuthError::invalid_request(format!(
+                "{}_type {} is not supported",
+                name, other
+            )))
+        }
+        None => {
+            return Err(OAuthError::invalid_request(format!(
+                "{}_type is missing",
+                name
+            )))
+        }
+    }
+    let claims = token::verify_issued_jwt(state, token).ok_or_else(|| {
+        OAuthError::invalid_request(format!("{} is not issued by this fakeidp", name))
+    })?;
+    if token::is_expired(&claims) {
+        return Err(OAuthError::invalid_request(format!("{} is expired", name)));
+    }
+    if !claims["sub"].is_string() {
+        return Err(OAuthError::invalid_request(format!(
+            "{} has no sub claim",
+            name
+        )));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{create_token, Authorization};
+    use actix_web::{http, test, web, App, Error};
+    use biscuit::jws::Secret;
+    use biscuit::{Empty, JWT};
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        web::Data::new(AppState::new(rsa_keys, exposed_host))
+    }
+
+    fn create_user_token(state: &AppState, client_id: &str, sub: &str) -> String {
+        token::create_access_token(
+            state,
+            &Authorization {
+                client_id: client_id.to_string(),
+                sub: sub.to_string(),
+                name: "Arie Ministrone".to_string(),
+                scope: "openid".to_string(),
+                nonce: None,
+                ..Authorization::default()
+            },
+        )
+    }
+
+    fn create_exchange_request(body: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(format!(
+                "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&client_id=gateway&{}",
+                body
+            ))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_token_exchange_delegation() -> Result<(), Error> {
+        let state = create_state();
+        let subject_token = create_user_token(&state, "webapp", "arie");
+        let actor_token = create_user_token(&state, "gateway", "gateway-service");
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = create_exchange_request(&format!(
+            "subject_token={}&subject_token_type={}&actor_token={}&actor_token_type={}&audience=orders-api&scope=orders:read",
+            subject_token, ACCESS_TOKEN_TYPE, actor_token, ACCESS_TOKEN_TYPE
+        ))
+        .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["issued_token_type"], ACCESS_TOKEN_TYPE);
+        assert_eq!(p["token_type"], "Bearer");
+
+        let access_token = JWT::<Value, Empty>::new_encoded(p["access_token"].as_str().unwrap());
+        let claims = serde_json::to_value(access_token.unverified_payload().unwrap()).unwrap();
+        assert_eq!(claims["sub"], "arie");
+        assert_eq!(claims["aud"], "orders-api");
+        assert_eq!(claims["scope"], "orders:read");
+        assert_eq!(claims["act"]["sub"], "gateway-service");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_token_exchange_foreign_token() -> Result<(), Error> {
+        let state = create_state();
+        let foreign_token = token::create_jwt(
+            &state.rsa_key_pair,
+            json!({ "iss": "https://accounts.example.com", "sub": "arie" }),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let req = create_exchange_request(&format!(
+            "subject_token={}&subject_token_type={}",
+            foreign_token, ACCESS_TOKEN_TYPE
+        ))
+        .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_request");
+
+        Ok(())
+    }
+}
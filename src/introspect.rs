@@ -0,0 +1,241 @@
+use crate::errors::OAuthError;
+use crate::refresh;
+use crate::token::{self, ClientCredentials};
+use crate::AppState;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+/// Form encoded parameters of an introspection request (RFC 7662 section 2.1).
+#[derive(Deserialize)]
+pub struct IntrospectionParameters {
+    #[serde(flatten)]
+    client: ClientCredentials,
+    token: Option<String>,
+}
+
+/// The introspection endpoint of RFC 7662 section 2, for the client of an API that gets a token presented.
+/// A token fakeidp did not issue, or that is expired or revoked, is not active.
+pub async fn introspect(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<IntrospectionParameters>,
+) -> Result<HttpResponse, Error> {
+    let params = form.into_inner();
+    let client_id = token::authenticate_client(&state, &req, &params.client)?;
+    // Only a client that presented its secret can learn about tokens, see RFC 7662 section 2.1.
+    if state
+        .config
+        .client(&client_id)
+        .filter(|client| client.client_secret.is_some())
+        .is_none()
+    {
+        return Err(OAuthError::invalid_client(
+            "introspection is only allowed for registered clients with a secret",
+        )
+        .into());
+    }
+    let token = params
+        .token
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("token is missing"))?;
+    let introspection = refresh_token_introspection(&state, token)
+        .or_else(|| jwt_introspection(&state, token))
+        .unwrap_or_else(|| json!({ "active": false }));
+    Ok(HttpResponse::Ok().json(introspection))
+}
+
+/// The claims of a signed access token, the client is the client_id claim or else the audience.
+/// ID tokens, logout tokens and the other JWTs fakeidp signs are not active, only those typed as access token.
+fn jwt_introspection(state: &AppState, token: &str) -> Option<Value> {
+    if !token::is_access_token_jwt(token) {
+        return None;
+    }
+    let claims =
+        token::verify_issued_jwt(state, token).filter(|claims| !token::is_expired(claims))?;
+    let client_id = match claims.get("client_id") {
+        Some(client_id) => client_id.clone(),
+        None => claims["aud"].clone(),
+    };
+    let mut introspection = json!({
+        "active": true,
+        "client_id": client_id,
+        "token_type": "Bearer",
+    });
+    for name in ["scope", "sub", "exp", "iat", "aud", "iss", "jti"] {
+        if let Some(value) = claims.get(name) {
+            introspection[name] = value.clone();
+        }
+    }
+    Some(introspection)
+}
+
+/// A refresh token is active until it expires, or is used with rotation or revoked with its family.
+fn refresh_token_introspection(state: &AppState, token: &str) -> Option<Value> {
+    let (authorization, expires_at) = refresh::active(state, token)?;
+    Some(json!({
+        "active": true,
+        "scope": authorization.scope,
+        "client_id": authorization.client_id,
+        "sub": authorization.sub,
+        "exp": expires_at,
+        "aud": authorization.client_id,
+        "iss": state.exposed_host,
+        "token_type": "refresh_token",
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Client, Config, TrustedIssuer};
+    use crate::discovery::create_jwk_set;
+    use crate::token::Authorization;
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+
+    #[actix_rt::test]
+    async fn test_route_introspect() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            clients: vec![Client {
+                client_id: "gateway".to_string(),
+                client_secret: Some("gateway-secret".to_string()),
+                ..Client::default()
+            }],
+            trusted_issuers: vec![TrustedIssuer {
+                issuer: "https://partner.example.com".to_string(),
+                jwks: create_jwk_set(rsa_keys.clone()),
+            }],
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::with_config(rsa_keys, exposed_host, config));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(introspect)))
+                .service(web::resource("/token").route(web::post().to(token::create_token))),
+        )
+        .await;
+        let authorization = Authorization {
+            client_id: "webshop".to_string(),
+            sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            scope: "openid offline_access".to_string(),
+            ..Authorization::default()
+        };
+        let access_token = token::create_access_token(&state, &authorization);
+        let refresh_token = refresh::issue(&state, &authorization).unwrap();
+        let iat = token::now() - 2 * token::TOKEN_LIFETIME;
+        let expired = token::create_access_token_jwt(
+            &state.rsa_key_pair,
+            json!({ "iss": "http://localhost:8080", "sub": "arie", "iat": iat, "exp": iat + 1 }),
+        );
+        // Signed with the same key, but for another issuer.
+        let foreign = token::create_access_token_jwt(
+            &Secret::rsa_keypair_from_file("./keys/private_key.der").unwrap(),
+            json!({ "iss": "https://accounts.example.com", "sub": "arie" }),
+        );
+        let id_token = token::create_id_token(&state, &authorization, Some(&access_token), None);
+        let logout_token = token::create_jwt(
+            &state.rsa_key_pair,
+            json!({
+                "iss": "http://localhost:8080",
+                "aud": "webshop",
+                "iat": token::now(),
+                "exp": token::now() + token::TOKEN_LIFETIME,
+                "jti": "8E5E1F4C-4D3B-4E8A-9C1D-2B7A6F0E3D91",
+                "events": { "http://schemas.openid.net/event/backchannel-logout": {} },
+            }),
+        );
+        let token_request = |body: String| async {
+            let req = test::TestRequest::post()
+                .uri("/token")
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .set_payload(body)
+                .to_request();
+            let response: Value = test::call_and_read_body_json(&app, req).await;
+            response["access_token"].as_str().unwrap().to_string()
+        };
+        // Without a scope, the access token of an assertion has neither a scope nor a jti.
+        let assertion = token::create_jwt(
+            &state.rsa_key_pair,
+            json!({
+                "iss": "https://partner.example.com",
+                "sub": "arie",
+                "aud": "http://localhost:8080/token",
+                "exp": token::now() + 60,
+                "jti": "assertion-1"
+            }),
+        );
+        let jwt_bearer_token = token_request(format!(
+            "grant_type=urn:ietf:params:oauth:grant-type:jwt-bearer&assertion={}",
+            assertion
+        ))
+        .await;
+        let exchanged_token = token_request(format!(
+            "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&client_id=gateway&client_secret=gateway-secret&subject_token={}&subject_token_type=urn:ietf:params:oauth:token-type:id_token",
+            id_token
+        ))
+        .await;
+        let introspect = |token: &str| {
+            test::TestRequest::post()
+                .uri("/")
+                .set_form([
+                    ("client_id", "gateway"),
+                    ("client_secret", "gateway-secret"),
+                    ("token", token),
+                ])
+                .to_request()
+        };
+
+        let introspection: Value =
+            test::call_and_read_body_json(&app, introspect(&access_token)).await;
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["client_id"], "webshop");
+        assert_eq!(introspection["sub"], "F82E617D-DEAF-4EE6-8F96-CF3409060CA2");
+        assert_eq!(introspection["scope"], "openid offline_access");
+        assert_eq!(introspection["iss"], "http://localhost:8080");
+        assert_eq!(introspection["token_type"], "Bearer");
+
+        let introspection: Value =
+            test::call_and_read_body_json(&app, introspect(&refresh_token)).await;
+        assert_eq!(introspection["active"], true);
+        assert_eq!(introspection["token_type"], "refresh_token");
+
+        for (token, sub) in [
+            (&jwt_bearer_token, "arie"),
+            (&exchanged_token, "F82E617D-DEAF-4EE6-8F96-CF3409060CA2"),
+        ] {
+            let introspection: Value = test::call_and_read_body_json(&app, introspect(token)).await;
+            assert_eq!(introspection["active"], true);
+            assert_eq!(introspection["sub"], sub);
+        }
+
+        // A revoked refresh token is removed with its whole family.
+        state.refresh_tokens.lock().unwrap().clear();
+        for token in [
+            refresh_token.as_str(),
+            &expired,
+            &foreign,
+            &id_token,
+            &logout_token,
+            "not-a-token",
+        ] {
+            let introspection: Value = test::call_and_read_body_json(&app, introspect(token)).await;
+            assert_eq!(introspection, json!({ "active": false }));
+        }
+
+        for credentials in [[("client_id", "gateway")], [("client_id", "anyone")]] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .set_form([credentials[0], ("token", access_token.as_str())])
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use crate::auth::AuthParameters;
+use crate::errors::OAuthError;
+use crate::token;
+use crate::AppState;
+use serde_json::{Map, Value};
+
+/// Builds the authorization request from the request object of JAR (RFC 9101) and the other parameters.
+/// The parameters inside the signed request object take precedence over the ones next to it.
+pub fn authorization_request(
+    state: &AppState,
+    parameters: &str,
+    request: &str,
+) -> Result<AuthParameters, OAuthError> {
+    let parameters: Vec<(String, String)> = serde_urlencoded::from_str(parameters)
+        .map_err(|err| OAuthError::invalid_request(err.to_string()))?;
+    let client_id = parameters
+        .iter()
+        .find(|(name, _)| name == "client_id")
+        .map(|(_, value)| value.clone())
+        .ok_or_else(|| OAuthError::invalid_request("client_id is missing"))?;
+    let claims = verify(state, &client_id, request)?;
+
+    let mut merged: Map<String, Value> = parameters
+        .into_iter()
+        .map(|(name, value)| (name, Value::String(value)))
+        .collect();
+    for (name, value) in claims {
+        let value = match value {
+            Value::String(value) => Value::String(value),
+            other => Value::String(other.to_string()),
+        };
+        merged.insert(name, value);
+    }
+    serde_json::from_value(Value::Object(merged))
+        .map_err(|err| OAuthError::invalid_request(err.to_string()))
+}
+
+/// Verifies the request object with the keys the client registered and returns its claims.
+fn verify(
+    state: &AppState,
+    client_id: &str,
+    request: &str,
+) -> Result<Map<String, Value>, OAuthError> {
+    let jwks = state
+        .config
+        .client(client_id)
+        .and_then(|client| client.jwks.as_ref())
+        .ok_or_else(|| invalid_request_object("the client has no registered jwks"))?;
+    let claims = token::verify_jwt(jwks, request)
+        .map_err(|_| invalid_request_object("request object is not signed by the client"))?;
+    if token::is_expired(&claims) {
+        return Err(invalid_request_object("request object is expired"));
+    }
+    for claim in ["client_id", "iss"] {
+        if claims[claim]
+            .as_str()
+            .is_some_and(|value| value != client_id)
+        {
+            return Err(invalid_request_object(format!(
+                "{} of the request object does not match the client_id",
+                claim
+            )));
+        }
+    }
+    let audiences = match &claims["aud"] {
+        Value::String(aud) => vec![aud.as_str()],
+        Value::Array(auds) => auds.iter().filter_map(|aud| aud.as_str()).collect(),
+        _ => vec![state.exposed_host.as_str()],
+    };
+    if !audiences.contains(&state.exposed_host.as_str()) {
+        return Err(invalid_request_object(format!(
+            "request object aud has to contain {}",
+            state.exposed_host
+        )));
+    }
+    match claims {
+        Value::Object(claims) => Ok(claims),
+        _ => Err(invalid_request_object("request object has no claims")),
+    }
+}
+
+/// Fetches a request object that is passed by reference, see RFC 9101 section 5.2.
+pub async fn fetch(state: &AppState, request_uri: &str) -> Result<String, OAuthError> {
+    let response = state
+        .http_client
+        .get(request_uri)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| OAuthError::new("invalid_request_uri", err.to_string()))?;
+    let request = response
+        .text()
+        .await
+        .map_err(|err| OAuthError::new("invalid_request_uri", err.to_string()))?;
+    Ok(request.trim().to_string())
+}
+
+fn invalid_request_object(description: impl Into<String>) -> OAuthError {
+    OAuthError::new("invalid_request_object", description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth;
+    use crate::config::{Client, Config};
+    use crate::discovery::create_jwk_set;
+    use actix_web::{http, test, web, App, Error};
+    use biscuit::jws::Secret;
+    use data_encoding::BASE64URL_NOPAD;
+    use serde_json::json;
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        // The client signs its request objects with the same key as fakeidp, to keep the test small.
+        let config = Config {
+            clients: vec![Client {
+                client_id: "webshop".to_string(),
+                jwks: Some(create_jwk_set(rsa_keys.clone())),
+                ..Client::default()
+            }],
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_request_object() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::get().to(auth))),
+        )
+        .await;
+
+        let request = token::create_jwt(
+            &state.rsa_key_pair,
+            json!({
+                "iss": "webshop",
+                "aud": "http://localhost:8080",
+                "client_id": "webshop",
+                "redirect_uri": "http://localhost:3000/callback",
+                "response_type": "code",
+                "scope": "openid",
+                "state": "signed-state"
+            }),
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?client_id=webshop&state=query-state&request={}",
+                request
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        assert!(body.contains("value=\"signed-state\""));
+        assert!(!body.contains("query-state"));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_auth_unsigned_request_object() -> Result<(), Error> {
+        let app = test::init_service(
+            App::new()
+                .app_data(create_state())
+                .service(web::resource("/").route(web::get().to(auth))),
+        )
+        .await;
+
+        let header = BASE64URL_NOPAD.encode(br#"{"alg":"none"}"#);
+        let payload = BASE64URL_NOPAD.encode(
+            br#"{"client_id":"webshop","redirect_uri":"http://localhost:3000/callback","response_type":"code","scope":"openid","state":"a-state"}"#,
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/?client_id=webshop&request={}.{}.",
+                header, payload
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_request_object");
+
+        Ok(())
+    }
+}
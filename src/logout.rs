@@ -0,0 +1,602 @@
+use crate::auth::{escape_html, hidden_field};
+use crate::errors::OAuthError;
+use crate::session::{self, Session};
+use crate::token;
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures::future::join_all;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Seconds a logout token is valid, it is delivered right away.
+const LOGOUT_TOKEN_LIFETIME: u64 = 120;
+/// Member of the events claim that makes a JWT a logout token.
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// The parameters of the end_session_endpoint of OIDC RP-Initiated Logout section 2.
+#[derive(Deserialize)]
+pub struct LogoutParameters {
+    id_token_hint: Option<String>,
+    client_id: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+}
+
+/// Logs the user out right away when the client passes an id_token_hint, otherwise the user has to confirm it first.
+pub async fn end_session(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    params: web::Query<LogoutParameters>,
+) -> Result<HttpResponse, Error> {
+    let hint = params.verified_hint(&state)?;
+    let redirect = params.post_logout_redirect(&state, hint.as_ref())?;
+    match hint {
+        Some(_) => Ok(logout(&state, &req, redirect).await),
+        None => Ok(confirmation_page(&params)),
+    }
+}
+
+/// Logs the user out after the confirmation page.
+pub async fn confirm(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<LogoutParameters>,
+) -> Result<HttpResponse, Error> {
+    let hint = form.verified_hint(&state)?;
+    let redirect = form.post_logout_redirect(&state, hint.as_ref())?;
+    Ok(logout(&state, &req, redirect).await)
+}
+
+impl LogoutParameters {
+    /// The claims of the id_token_hint, which has to be issued by fakeidp. It may be expired.
+    fn verified_hint(&self, state: &AppState) -> Result<Option<Value>, OAuthError> {
+        self.id_token_hint
+            .as_deref()
+            .filter(|hint| !hint.is_empty())
+            .map(|hint| {
+                token::verify_issued_jwt(state, hint).ok_or_else(|| {
+                    OAuthError::invalid_request(
+                        "id_token_hint is not an ID token issued by fakeidp",
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    /// The post_logout_redirect_uri with the state, when it is registered for the client of the request.
+    fn post_logout_redirect(
+        &self,
+        state: &AppState,
+        hint: Option<&Value>,
+    ) -> Result<Option<String>, OAuthError> {
+        let uri = match self.post_logout_redirect_uri.as_deref() {
+            Some(uri) if !uri.is_empty() => uri,
+            _ => return Ok(None),
+        };
+        let audience = hint.and_then(|claims| match &claims["aud"] {
+            Value::Array(auds) => auds.first().and_then(Value::as_str),
+            aud => aud.as_str(),
+        });
+        let client_id = match (self.client_id.as_deref(), audience) {
+            (Some(client_id), Some(audience)) if client_id != audience => {
+                return Err(OAuthError::invalid_request(
+                    "client_id is not the audience of the id_token_hint",
+                ))
+            }
+            (Some(client_id), _) | (None, Some(client_id)) => client_id,
+            (None, None) => {
+                return Err(OAuthError::invalid_request(
+                    "client_id or id_token_hint is required with a post_logout_redirect_uri",
+                ))
+            }
+        };
+        let registered = state.config.client(client_id).is_some_and(|client| {
+            client
+                .post_logout_redirect_uris
+                .iter()
+                .any(|registered| registered == uri)
+        });
+        if !registered {
+            return Err(OAuthError::invalid_request(
+                "post_logout_redirect_uri is not registered for the client",
+            ));
+        }
+
+        Ok(Some(match &self.state {
+            Some(logout_state) => with_parameters(uri, &[("state", logout_state)]),
+            None => uri.to_string(),
+        }))
+    }
+}
+
+/// Ends the session of the browser and sends it to the client, or shows that the user is logged out.
+/// The clients of the session get a logout token, and the page loads their front-channel logout before it continues to the client.
+async fn logout(state: &AppState, req: &HttpRequest, redirect: Option<String>) -> HttpResponse {
+    let ended = session::current(state, req).and_then(|(id, _)| session::end(state, &id));
+    let mut frontchannel_logouts = Vec::new();
+    if let Some(session) = ended {
+        backchannel_logout(state, &session).await;
+        frontchannel_logouts = frontchannel_logout_uris(state, &session);
+    }
+    let mut response = match redirect {
+        Some(location) if frontchannel_logouts.is_empty() => {
+            HttpResponse::build(StatusCode::SEE_OTHER)
+                .insert_header(("Location", location))
+                .finish()
+        }
+        redirect => logged_out_page(&frontchannel_logouts, redirect.as_deref()),
+    };
+    let _ = response.add_removal_cookie(&session::cookie(state, String::new()));
+    response
+}
+
+/// The frontchannel_logout_uri of every client of the session, with the iss and sid of OIDC Front-Channel Logout section 2.
+pub(crate) fn frontchannel_logout_uris(state: &AppState, session: &Session) -> Vec<String> {
+    session
+        .clients
+        .iter()
+        .filter_map(|client_id| state.config.client(client_id))
+        .filter_map(|client| client.frontchannel_logout_uri.as_deref())
+        .map(|uri| with_parameters(uri, &[("iss", &state.exposed_host), ("sid", &session.sid)]))
+        .collect()
+}
+
+/// The outcome of posting a logout token to the backchannel_logout_uri of a client.
+#[derive(Clone, Debug, Serialize)]
+pub struct LogoutDelivery {
+    pub client_id: String,
+    pub backchannel_logout_uri: String,
+    pub logout_token: String,
+    /// Why the client did not accept the logout token, none when it did.
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminLogoutParameters {
+    sub: Option<String>,
+    sid: Option<String>,
+}
+
+/// Ends the sessions of a sub or sid without a browser, the clients of those sessions get a logout token.
+pub async fn admin_logout(
+    state: web::Data<AppState>,
+    params: web::Form<AdminLogoutParameters>,
+) -> Result<HttpResponse, Error> {
+    let sub = params.sub.as_deref().filter(|sub| !sub.is_empty());
+    let sid = params.sid.as_deref().filter(|sid| !sid.is_empty());
+    if sub.is_none() && sid.is_none() {
+        return Err(OAuthError::invalid_request("sub or sid is required").into());
+    }
+    let sessions = session::end_all(&state, sub, sid);
+    let deliveries: Vec<LogoutDelivery> = join_all(
+        sessions
+            .iter()
+            .map(|session| backchannel_logout(&state, session)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+/// Ends the sessions that reached their lifetime or idle timeout, their clients get a logout token like for a logout.
+pub async fn end_expired_sessions(state: &AppState) {
+    let sessions = session::end_expired(state);
+    join_all(
+        sessions
+            .iter()
+            .map(|session| backchannel_logout(state, session)),
+    )
+    .await;
+}
+
+/// All back-channel logout notifications that were sent, so a tester can see what the clients got.
+pub async fn deliveries(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let deliveries = state.logout_deliveries.lock().unwrap();
+    Ok(HttpResponse::Ok().json(&*deliveries))
+}
+
+/// Posts a logout token to the backchannel_logout_uri of every client of the session, see OIDC Back-Channel Logout section 2.5.
+/// The clients are called at the same time, so one that does not answer only holds up the logout until the timeout.
+pub(crate) async fn backchannel_logout(state: &AppState, session: &Session) -> Vec<LogoutDelivery> {
+    let deliveries = join_all(session.clients.iter().filter_map(|client_id| {
+        let uri = state
+            .config
+            .client(client_id)?
+            .backchannel_logout_uri
+            .clone()?;
+        Some(deliver_logout_token(state, client_id, uri, session))
+    }))
+    .await;
+    state
+        .logout_deliveries
+        .lock()
+        .unwrap()
+        .extend(deliveries.iter().cloned());
+    deliveries
+}
+
+async fn deliver_logout_token(
+    state: &AppState,
+    client_id: &str,
+    uri: String,
+    session: &Session,
+) -> LogoutDelivery {
+    let logout_token = create_logout_token(state, client_id, session);
+    let error = state
+        .http_client
+        .post(&uri)
+        .form(&[("logout_token", &logout_token)])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .err()
+        .map(|err| err.to_string());
+    match &error {
+        None => log::info!("Back-channel logout of {} delivered to {}", client_id, uri),
+        Some(error) => log::warn!(
+            "Back-channel logout of {} to {} failed: {}",
+            client_id,
+            uri,
+            error
+        ),
+    }
+    LogoutDelivery {
+        client_id: client_id.to_string(),
+        backchannel_logout_uri: uri,
+        logout_token,
+        error,
+    }
+}
+
+/// The logout token of OIDC Back-Channel Logout section 2.4, it has the events claim and never a nonce.
+fn create_logout_token(state: &AppState, client_id: &str, session: &Session) -> String {
+    let iat = token::now();
+    token::create_jwt(
+        &state.rsa_key_pair,
+        json!({
+            "iss": state.exposed_host,
+            "aud": client_id,
+            "iat": iat,
+            "exp": iat + LOGOUT_TOKEN_LIFETIME,
+            "jti": nanoid::nanoid!(),
+            "sub": session.sub,
+            "sid": session.sid,
+            "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+        }),
+    )
+}
+
+fn with_parameters(uri: &str, params: &[(&str, &String)]) -> String {
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    let encoded = serde_urlencoded::to_string(params).unwrap_or_default();
+    format!("{}{}{}", uri, separator, encoded)
+}
+
+fn logged_out_page(frontchannel_logouts: &[String], redirect: Option<&str>) -> HttpResponse {
+    // The iframes get a moment to load before the browser continues to the client.
+    let (refresh, continue_link) = match redirect {
+        Some(location) => (
+            format!(
+                "<meta http-equiv=\"refresh\" content=\"2;url={}\">",
+                escape_html(location)
+            ),
+            format!(
+                "<a class=\"idp-btn theme-btn--primary\" href=\"{}\">Continue</a>",
+                escape_html(location)
+            ),
+        ),
+        None => (String::new(), String::new()),
+    };
+    let body = format!(
+        include_str!("../template/logged_out.html"),
+        refresh = refresh,
+        continue_link = continue_link,
+        frontchannel_logouts = frontchannel_iframes(frontchannel_logouts)
+    );
+    HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+/// Hidden iframes that load the frontchannel_logout_uris in the browser.
+pub(crate) fn frontchannel_iframes(frontchannel_logouts: &[String]) -> String {
+    frontchannel_logouts
+        .iter()
+        .map(|uri| {
+            format!(
+                "<iframe src=\"{}\" style=\"display:none\"></iframe>\n",
+                escape_html(uri)
+            )
+        })
+        .collect()
+}
+
+fn confirmation_page(params: &LogoutParameters) -> HttpResponse {
+    let hidden_fields: String = [
+        ("client_id", &params.client_id),
+        ("post_logout_redirect_uri", &params.post_logout_redirect_uri),
+        ("state", &params.state),
+    ]
+    .iter()
+    .filter_map(|(name, value)| value.as_deref().map(|value| hidden_field(name, value)))
+    .collect();
+    let body = format!(
+        include_str!("../template/logout.html"),
+        hidden_fields = hidden_fields
+    );
+    HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Client, Config};
+    use crate::token::Authorization;
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            clients: vec![
+                Client {
+                    client_id: "webshop".to_string(),
+                    post_logout_redirect_uris: vec!["http://localhost:3000/logged-out".to_string()],
+                    ..Client::default()
+                },
+                Client {
+                    client_id: "orders".to_string(),
+                    frontchannel_logout_uri: Some("http://localhost:4000/logout".to_string()),
+                    // Nothing listens here, so the delivery fails.
+                    backchannel_logout_uri: Some("http://127.0.0.1:1/logout".to_string()),
+                    ..Client::default()
+                },
+            ],
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_logout() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new().app_data(state.clone()).service(
+                web::resource("/logout")
+                    .route(web::get().to(end_session))
+                    .route(web::post().to(confirm)),
+            ),
+        )
+        .await;
+        let (_, _, cookie) = session::start(
+            &state,
+            "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            "Arie Ministrone".to_string(),
+            None,
+            Vec::new(),
+        );
+        let id_token = token::create_id_token(
+            &state,
+            &Authorization {
+                client_id: "webshop".to_string(),
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                ..Authorization::default()
+            },
+            None,
+            None,
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/logout?post_logout_redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fother")
+            .insert_header(("Cookie", format!("{}={}", cookie.name(), cookie.value())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/logout?id_token_hint={}&post_logout_redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Flogged-out&state=a-state",
+                id_token
+            ))
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert_eq!(location, "http://localhost:3000/logged-out?state=a-state");
+        assert!(state.sessions.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_logout_confirmation() -> Result<(), Error> {
+        let app = test::init_service(
+            App::new()
+                .app_data(create_state())
+                .service(web::resource("/logout").route(web::get().to(end_session))),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/logout?client_id=webshop&post_logout_redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Flogged-out")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        assert!(body.contains("value=\"http://localhost:3000/logged-out\""));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_logout_frontchannel() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/logout").route(web::get().to(end_session))),
+        )
+        .await;
+        let (_, session, cookie) = session::start(
+            &state,
+            "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            "Arie Ministrone".to_string(),
+            None,
+            Vec::new(),
+        );
+        session::join(&state, &session.sid, "webshop");
+        session::join(&state, &session.sid, "orders");
+        let id_token = token::create_id_token(
+            &state,
+            &Authorization {
+                client_id: "webshop".to_string(),
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                sid: Some(session.sid.clone()),
+                ..Authorization::default()
+            },
+            None,
+            None,
+        );
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/logout?id_token_hint={}&post_logout_redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Flogged-out",
+                id_token
+            ))
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        // Only the client with a frontchannel_logout_uri gets an iframe.
+        assert_eq!(body.matches("<iframe").count(), 1);
+        assert!(body.contains(&format!(
+            "src=\"http://localhost:4000/logout?iss=http%3A%2F%2Flocalhost%3A8080&amp;sid={}\"",
+            session.sid
+        )));
+        assert!(body.contains("url=http://localhost:3000/logged-out"));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_end_expired_sessions() {
+        let state = create_state();
+        let (id, session, _) = session::start(
+            &state,
+            "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            "Arie Ministrone".to_string(),
+            None,
+            Vec::new(),
+        );
+        session::join(&state, &session.sid, "orders");
+        session::start(
+            &state,
+            "bert".to_string(),
+            "Bert".to_string(),
+            None,
+            Vec::new(),
+        );
+
+        end_expired_sessions(&state).await;
+        assert_eq!(state.sessions.lock().unwrap().len(), 2);
+        assert!(state.logout_deliveries.lock().unwrap().is_empty());
+
+        // The session of arie reached its lifetime.
+        state
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .auth_time = 0;
+        end_expired_sessions(&state).await;
+        assert_eq!(state.sessions.lock().unwrap().len(), 1);
+        let deliveries = state.logout_deliveries.lock().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].client_id, "orders");
+        let claims = token::verify_issued_jwt(&state, &deliveries[0].logout_token).unwrap();
+        assert_eq!(claims["sid"], session.sid.as_str());
+    }
+
+    #[actix_rt::test]
+    async fn test_route_admin_logout() -> Result<(), Error> {
+        let state = create_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(
+                    web::resource("/admin/logout")
+                        .route(web::get().to(deliveries))
+                        .route(web::post().to(admin_logout)),
+                )
+                .service(
+                    web::resource("/check_session/state")
+                        .route(web::get().to(session::browser_state)),
+                ),
+        )
+        .await;
+        let (_, session, cookie) = session::start(
+            &state,
+            "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            "Arie Ministrone".to_string(),
+            None,
+            Vec::new(),
+        );
+        session::join(&state, &session.sid, "webshop");
+        session::join(&state, &session.sid, "orders");
+        let browser_state_request = || {
+            test::TestRequest::get()
+                .uri("/check_session/state")
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let p: Value = test::call_and_read_body_json(&app, browser_state_request()).await;
+        assert_eq!(p["browser_state"], session.sid.as_str());
+
+        let req = test::TestRequest::post()
+            .uri("/admin/logout")
+            .set_form([("sub", "F82E617D-DEAF-4EE6-8F96-CF3409060CA2")])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert!(state.sessions.lock().unwrap().is_empty());
+        // The browser state changes, so the check_session_iframe answers changed.
+        let p: Value = test::call_and_read_body_json(&app, browser_state_request()).await;
+        assert_eq!(p["browser_state"], "");
+
+        let req = test::TestRequest::get().uri("/admin/logout").to_request();
+        let deliveries: Value = test::call_and_read_body_json(&app, req).await;
+        // Only the client with a backchannel_logout_uri gets a logout token.
+        let deliveries = deliveries.as_array().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0]["client_id"], "orders");
+        assert!(deliveries[0]["error"].is_string());
+        let logout_token = deliveries[0]["logout_token"].as_str().unwrap();
+        let claims = token::verify_issued_jwt(&state, logout_token).unwrap();
+        assert_eq!(claims["aud"], "orders");
+        assert_eq!(claims["sid"], session.sid.as_str());
+        assert_eq!(claims["sub"], "F82E617D-DEAF-4EE6-8F96-CF3409060CA2");
+        assert!(claims["events"][BACKCHANNEL_LOGOUT_EVENT].is_object());
+        assert!(claims.get("nonce").is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/admin/logout")
+            .set_form([("sub", "")])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+}
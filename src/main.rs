@@ -4,26 +4,86 @@ use actix_files as fs;
 use actix_web::{middleware, web, App, HttpServer};
 use biscuit::jws::Secret;
 use clap::Parser;
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
 
+mod assertion;
 mod auth;
 mod checks;
+mod ciba;
+mod claims;
+mod config;
+mod device;
 mod discovery;
 mod errors;
+mod exchange;
+mod introspect;
+mod jar;
+mod logout;
+mod par;
+mod refresh;
+mod response;
+mod session;
 mod token;
 mod userinfo;
 
+// Seconds fakeidp waits for a client it calls, like a request_uri or a notification endpoint.
+const OUTGOING_REQUEST_TIMEOUT: u64 = 5;
+// Seconds between the checks for sessions that expired, whose clients then get a logout token.
+const SESSION_EXPIRY_INTERVAL: u64 = 10;
+
 //AppState object is initialized for the App and passed with every request that has a parameter with the AppState as type.
 pub struct AppState {
     rsa_key_pair: biscuit::jws::Secret,
     exposed_host: String,
+    config: config::Config,
+    // Client for the requests to the clients, with a timeout so one that does not answer cannot hold up a response.
+    http_client: reqwest::Client,
+    // Authorization codes issued by the login that are not yet exchanged at the token endpoint.
+    codes: Mutex<HashMap<String, auth::AuthorizationCode>>,
+    // Refresh tokens by their value, including the used ones of a rotating family.
+    refresh_tokens: Mutex<HashMap<String, refresh::RefreshToken>>,
+    // Device authorization requests by their device_code.
+    device_codes: Mutex<HashMap<String, device::DeviceAuthorization>>,
+    // Issuer and jti of the JWT assertions that were used, with their expiry.
+    assertion_ids: Mutex<HashMap<String, u64>>,
+    // Pushed authorization requests by their request_uri.
+    pushed_requests: Mutex<HashMap<String, par::PushedRequest>>,
+    // Backchannel authentication requests by their auth_req_id.
+    backchannel_authentications: Mutex<HashMap<String, ciba::BackchannelAuthentication>>,
+    // Browser sessions by the id in their session cookie.
+    sessions: Mutex<HashMap<String, session::Session>>,
+    // Claims released at the userinfo endpoint by the jti of their access token.
+    userinfo: Mutex<HashMap<String, userinfo::ReleasedClaims>>,
+    // Results of the back-channel logout notifications that were sent, in order.
+    logout_deliveries: Mutex<Vec<logout::LogoutDelivery>>,
 }
 
 impl AppState {
     pub fn new(rsa_keys: Secret, exposed_host: String) -> Self {
+        Self::with_config(rsa_keys, exposed_host, config::Config::default())
+    }
+
+    pub fn with_config(rsa_keys: Secret, exposed_host: String, config: config::Config) -> Self {
         Self {
             rsa_key_pair: rsa_keys.clone(),
             exposed_host: exposed_host.clone(),
+            config,
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(OUTGOING_REQUEST_TIMEOUT))
+                .build()
+                .expect("Cannot create the HTTP client"),
+            codes: Mutex::new(HashMap::new()),
+            refresh_tokens: Mutex::new(HashMap::new()),
+            device_codes: Mutex::new(HashMap::new()),
+            assertion_ids: Mutex::new(HashMap::new()),
+            pushed_requests: Mutex::new(HashMap::new()),
+            backchannel_authentications: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            userinfo: Mutex::new(HashMap::new()),
+            logout_deliveries: Mutex::new(Vec::new()),
         }
     }
 }
@@ -50,6 +110,30 @@ struct Args {
     #[arg(short = 'f', long, default_value = "./static")]
     folder: String,
     // default value './static'
+    /// JSON file with the registered clients, users and trusted issuers
+    #[arg(short = 'c', long, env = "CONFIG")]
+    config: Option<String>,
+    // no default, without it nothing is registered
+    /// Rotate refresh tokens on every use, a reused refresh token revokes all tokens of its family
+    #[arg(long, env = "REFRESH_TOKEN_ROTATION")]
+    refresh_token_rotation: bool,
+    // default value false
+    /// Absolute lifetime in seconds of a refresh token, counted from the first one issued for a login
+    #[arg(long, env = "REFRESH_TOKEN_LIFETIME", default_value = "2592000")]
+    refresh_token_lifetime: u64,
+    // default value 30 days
+    /// Sliding lifetime in seconds of a refresh token, extended every time it is used
+    #[arg(long, env = "REFRESH_TOKEN_IDLE_LIFETIME", default_value = "86400")]
+    refresh_token_idle_lifetime: u64,
+    // default value 1 day
+    /// Absolute lifetime in seconds of the browser session at fakeidp, counted from the login
+    #[arg(long, env = "SESSION_LIFETIME", default_value = "28800")]
+    session_lifetime: u64,
+    // default value 8 hours
+    /// Seconds the browser session at fakeidp ends after when it is not used
+    #[arg(long, env = "SESSION_IDLE_TIMEOUT", default_value = "1800")]
+    session_idle_timeout: u64,
+    // default value 30 minutes
 }
 
 /*
@@ -61,17 +145,46 @@ async fn main() -> std::io::Result<()> {
 
     let bind = format!("{}:{}", args.bind_host, args.bind_port);
 
-    std::env::set_var("RUST_LOG", "actix_web=info");
+    std::env::set_var("RUST_LOG", "actix_web=info,fakeidp=info");
     env_logger::init();
 
     let default_keyfile = "./keys/private_key.der".to_string();
-    let keyfile_to_use = &args.keyfile.unwrap_or_else(|| default_keyfile);
+    let keyfile_to_use = &args.keyfile.unwrap_or(default_keyfile);
     let rsa_keys = Secret::rsa_keypair_from_file(keyfile_to_use).expect("Cannot read RSA keypair");
 
     let jwk_set = discovery::create_jwk_set(rsa_keys.clone());
 
     let oidc = Oidc::new(OidcConfig::Jwks(jwk_set)).await.unwrap();
 
+    let config = config::Config {
+        refresh_token_rotation: args.refresh_token_rotation,
+        refresh_token_lifetime: args.refresh_token_lifetime,
+        refresh_token_idle_lifetime: args.refresh_token_idle_lifetime,
+        session_lifetime: args.session_lifetime,
+        session_idle_timeout: args.session_idle_timeout,
+        ..match &args.config {
+            Some(config_file) => {
+                config::Config::from_file(config_file).expect("Cannot read config file")
+            }
+            None => config::Config::default(),
+        }
+    };
+    // The state is shared by all workers, so pending authorizations are found by whichever worker gets the request.
+    let app_state = web::Data::new(AppState::with_config(
+        rsa_keys,
+        args.exposed_host.clone(),
+        config,
+    ));
+
+    let expiry_state = app_state.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(SESSION_EXPIRY_INTERVAL));
+        loop {
+            interval.tick().await;
+            logout::end_expired_sessions(&expiry_state).await;
+        }
+    });
+
     let mut user = String::from_utf8(Command::new("whoami").output().unwrap().stdout).unwrap();
     user.pop();
     println!("FakeIdP endpoint bound to {} as user {}!", bind, user);
@@ -85,14 +198,46 @@ async fn main() -> std::io::Result<()> {
             .wrap(middleware::Logger::default())
             .wrap(cors)
             .app_data(web::Data::new(web::JsonConfig::default().limit(4096)))
-            .app_data(web::Data::new(AppState::new(
-                rsa_keys.clone(),
-                args.exposed_host.clone(),
-            )))
+            .app_data(app_state.clone())
             .app_data(oidc.clone())
             .service(web::resource("/auth/login").route(web::post().to(auth::login)))
+            .service(web::resource("/auth/consent").route(web::post().to(auth::consent)))
             .service(web::resource("/auth").route(web::get().to(auth::auth)))
+            .service(web::resource("/introspect").route(web::post().to(introspect::introspect)))
+            .service(web::resource("/par").route(web::post().to(par::pushed_authorization_request)))
             .service(web::resource("/token").route(web::post().to(token::create_token)))
+            .service(
+                web::resource("/device_authorization")
+                    .route(web::post().to(device::device_authorization)),
+            )
+            .service(
+                web::resource("/device")
+                    .route(web::get().to(device::verification))
+                    .route(web::post().to(device::approve)),
+            )
+            .service(
+                web::resource("/bc-authorize")
+                    .route(web::post().to(ciba::backchannel_authentication)),
+            )
+            .service(web::resource("/admin/ciba").route(web::get().to(ciba::pending)))
+            .service(
+                web::resource("/admin/ciba/{auth_req_id}/{action}")
+                    .route(web::post().to(ciba::decide)),
+            )
+            .service(web::resource("/check_session").route(web::get().to(session::check_session)))
+            .service(
+                web::resource("/check_session/state").route(web::get().to(session::browser_state)),
+            )
+            .service(
+                web::resource("/logout")
+                    .route(web::get().to(logout::end_session))
+                    .route(web::post().to(logout::confirm)),
+            )
+            .service(
+                web::resource("/admin/logout")
+                    .route(web::get().to(logout::deliveries))
+                    .route(web::post().to(logout::admin_logout)),
+            )
             .service(web::resource("/userinfo").route(web::get().to(userinfo::user_info)))
             .service(
                 web::resource("/.well-known/openid-configuration")
@@ -0,0 +1,227 @@
+use crate::auth::AuthParameters;
+use crate::errors::OAuthError;
+use crate::jar;
+use crate::token::{self, ClientCredentials};
+use crate::AppState;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+
+/// Lifetime in seconds of a pushed authorization request before it has to be used at the authorization endpoint.
+pub const PAR_LIFETIME: u64 = 60;
+
+const REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+
+/// An authorization request that was pushed by the client and is waiting for the browser to come by.
+pub struct PushedRequest {
+    pub request: AuthParameters,
+    pub expires_at: u64,
+}
+
+/// The client authentication and request object of a pushed authorization request, the other parameters are the authorization request.
+#[derive(Deserialize)]
+pub struct PushedRequestParameters {
+    #[serde(flatten)]
+    client: ClientCredentials,
+    request: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushedAuthorizationResponse {
+    request_uri: String,
+    expires_in: u64,
+}
+
+/// The pushed authorization request endpoint of RFC 9126 section 2.
+pub async fn pushed_authorization_request(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let params: PushedRequestParameters = serde_urlencoded::from_bytes(&body)
+        .map_err(|err| OAuthError::invalid_request(err.to_string()))?;
+    let client_id = token::authenticate_client(&state, &req, &params.client)?;
+    let request: AuthParameters = match &params.request {
+        // A pushed request can be a signed request object too, see RFC 9126 section 3.
+        Some(request) => {
+            let body = std::str::from_utf8(&body)
+                .map_err(|err| OAuthError::invalid_request(err.to_string()))?;
+            jar::authorization_request(&state, body, request)?
+        }
+        None => serde_urlencoded::from_bytes(&body)
+            .map_err(|err| OAuthError::invalid_request(err.to_string()))?,
+    };
+    if request.client_id != client_id {
+        return Err(OAuthError::invalid_request(
+            "client_id does not match the authenticated client",
+        )
+        .into());
+    }
+    if serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .unwrap_or_default()
+        .iter()
+        .any(|(name, _)| name == "request_uri")
+    {
+        // RFC 9126 section 2.1: a pushed request cannot refer to another request.
+        return Err(OAuthError::invalid_request(
+            "request_uri is not allowed in a pushed authorization request",
+        )
+        .into());
+    }
+    request.validate().map_err(|(_, error)| error)?;
+
+    let request_uri = format!("{}{}", REQUEST_URI_PREFIX, nanoid::nanoid!(32));
+    let now = token::now();
+    let mut pushed_requests = state.pushed_requests.lock().unwrap();
+    pushed_requests.retain(|_, pushed| pushed.expires_at >= now);
+    pushed_requests.insert(
+        request_uri.clone(),
+        PushedRequest {
+            request,
+            expires_at: now + PAR_LIFETIME,
+        },
+    );
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(PushedAuthorizationResponse {
+            request_uri,
+            expires_in: PAR_LIFETIME,
+        }))
+}
+
+/// Tells whether a request_uri refers to a pushed authorization request, or to a request object elsewhere.
+pub fn is_pushed(request_uri: &str) -> bool {
+    request_uri.starts_with(REQUEST_URI_PREFIX)
+}
+
+/// Takes the pushed authorization request a request_uri refers to, it can only be used once.
+pub fn pushed_request(
+    state: &AppState,
+    client_id: &str,
+    request_uri: &str,
+) -> Result<AuthParameters, OAuthError> {
+    let mut pushed_requests = state.pushed_requests.lock().unwrap();
+    match pushed_requests.remove(request_uri) {
+        Some(pushed) if pushed.expires_at < token::now() => Err(OAuthError::new(
+            "invalid_request_uri",
+            "request_uri is expired",
+        )),
+        Some(pushed) if pushed.request.client_id != client_id => Err(OAuthError::invalid_request(
+            "request_uri was pushed by another client",
+        )),
+        Some(pushed) => Ok(pushed.request),
+        None => Err(OAuthError::new(
+            "invalid_request_uri",
+            "request_uri is unknown or already used",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth;
+    use crate::config::{Client, Config};
+    use actix_web::{http, test, App};
+    use biscuit::jws::Secret;
+    use serde_json::Value;
+
+    fn create_state() -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            clients: vec![Client {
+                client_id: "webshop".to_string(),
+                client_secret: Some("webshop-secret".to_string()),
+                require_pushed_authorization_requests: true,
+                ..Client::default()
+            }],
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    #[actix_rt::test]
+    async fn test_route_pushed_authorization_request() -> Result<(), Error> {
+        let app = test::init_service(
+            App::new()
+                .app_data(create_state())
+                .service(web::resource("/par").route(web::post().to(pushed_authorization_request)))
+                .service(web::resource("/auth").route(web::get().to(auth))),
+        )
+        .await;
+
+        // The client requires PAR, so a plain authorization request is refused.
+        let req = test::TestRequest::get()
+            .uri("/auth?client_id=webshop&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback&response_type=code&scope=openid&state=a-state")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SEE_OTHER);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.starts_with("http://localhost:3000/callback?error=invalid_request&"));
+
+        let req = test::TestRequest::post()
+            .uri("/par")
+            .set_form([
+                ("client_id", "webshop"),
+                ("client_secret", "webshop-secret"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::CREATED);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["expires_in"], 60);
+        let request_uri = p["request_uri"].as_str().unwrap().to_string();
+        assert!(request_uri.starts_with(REQUEST_URI_PREFIX));
+
+        let uri = format!(
+            "/auth?client_id=webshop&request_uri={}",
+            request_uri.replace(':', "%3A")
+        );
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap_or_default();
+        assert!(body.contains("value=\"http://localhost:3000/callback\""));
+
+        // A request_uri can only be used once.
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_request_uri");
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_route_pushed_authorization_request_wrong_secret() -> Result<(), Error> {
+        let app =
+            test::init_service(App::new().app_data(create_state()).service(
+                web::resource("/par").route(web::post().to(pushed_authorization_request)),
+            ))
+            .await;
+
+        let req = test::TestRequest::post()
+            .uri("/par")
+            .set_form([
+                ("client_id", "webshop"),
+                ("client_secret", "wrong"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("response_type", "code"),
+                ("scope", "openid"),
+                ("state", "a-state"),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use crate::errors::OAuthError;
+use crate::token::{self, Authorization, TokenParameters, TokenResponse};
+use crate::AppState;
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+
+/// A refresh token as kept by fakeidp.
+/// All refresh tokens that are rotated from the same original one share its family.
+pub struct RefreshToken {
+    pub authorization: Authorization,
+    pub family: String,
+    // Absolute end of the family, set when the first refresh token was issued.
+    pub family_expires_at: u64,
+    // Sliding end of this token, moved forward every time it is used.
+    pub expires_at: u64,
+    pub used: bool,
+}
+
+impl RefreshToken {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at < now || self.family_expires_at < now
+    }
+}
+
+/// The authorization and expiry of a refresh token that can still be used, not expired and not rotated or revoked.
+pub fn active(state: &AppState, value: &str) -> Option<(Authorization, u64)> {
+    let refresh_tokens = state.refresh_tokens.lock().unwrap();
+    refresh_tokens
+        .get(value)
+        .filter(|refresh_token| !refresh_token.used && !refresh_token.expired(token::now()))
+        .map(|refresh_token| {
+            (
+                refresh_token.authorization.clone(),
+                refresh_token.expires_at,
+            )
+        })
+}
+
+/// Issues a refresh token when the offline_access scope was granted.
+pub fn issue(state: &AppState, authorization: &Authorization) -> Option<String> {
+    if !token::has_scope(&authorization.scope, "offline_access") {
+        return None;
+    }
+    let now = token::now();
+    let family_expires_at = now + state.config.refresh_token_lifetime;
+    let mut refresh_tokens = state.refresh_tokens.lock().unwrap();
+    refresh_tokens.retain(|_, refresh_token| !refresh_token.expired(now));
+    Some(insert(
+        &mut refresh_tokens,
+        state,
+        authorization.clone(),
+        nanoid::nanoid!(),
+        family_expires_at,
+    ))
+}
+
+fn insert(
+    refresh_tokens: &mut HashMap<String, RefreshToken>,
+    state: &AppState,
+    authorization: Authorization,
+    family: String,
+    family_expires_at: u64,
+) -> String {
+    let value = nanoid::nanoid!(32);
+    let expires_at = family_expires_at.min(token::now() + state.config.refresh_token_idle_lifetime);
+    refresh_tokens.insert(
+        value.clone(),
+        RefreshToken {
+            authorization,
+            family,
+            family_expires_at,
+            expires_at,
+            used: false,
+        },
+    );
+    value
+}
+
+/// The refresh_token grant of RFC 6749 section 6.
+pub fn refresh_token_grant(
+    state: &AppState,
+    req: &HttpRequest,
+    params: TokenParameters,
+) -> Result<TokenResponse, OAuthError> {
+    let value = params
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("refresh_token is missing"))?;
+    let now = token::now();
+    let mut refresh_tokens = state.refresh_tokens.lock().unwrap();
+    let refresh_token = refresh_tokens
+        .get_mut(value)
+        .ok_or_else(|| OAuthError::invalid_grant("refresh_token is unknown or revoked"))?;
+
+    if refresh_token.used {
+        // A rotated token that comes back means it leaked, so nothing of the family can be trusted anymore.
+        let family = refresh_token.family.clone();
+        refresh_tokens.retain(|_, refresh_token| refresh_token.family != family);
+        return Err(OAuthError::invalid_grant(
+            "refresh_token was already used, all tokens of this login are revoked",
+        ));
+    }
+    if refresh_token.expired(now) {
+        refresh_tokens.remove(value);
+        return Err(OAuthError::invalid_grant("refresh_token is expired"));
+    }
+    if token::authenticate_client(state, req, &params.client)?
+        != refresh_token.authorization.client_id
+    {
+        return Err(OAuthError::invalid_grant(
+            "refresh_token was issued to another client",
+        ));
+    }
+
+    // The renewed refresh token keeps the granted scope, a narrowed scope only applies to the access token.
+    let granted = refresh_token.authorization.clone();
+    let mut authorization = granted.clone();
+    if let Some(scope) = &params.scope {
+        // The scope of a refreshed token can only be narrowed, see RFC 6749 section 6.
+        if let Some(extra) = scope
+            .split_whitespace()
+            .find(|s| !token::has_scope(&authorization.scope, s))
+        {
+            return Err(OAuthError::new(
+                "invalid_scope",
+                format!("scope {} was not granted originally", extra),
+            ));
+        }
+        authorization.scope = scope.clone();
+    }
+
+    let renewed = if state.config.refresh_token_rotation {
+        refresh_token.used = true;
+        let family = refresh_token.family.clone();
+        let family_expires_at = refresh_token.family_expires_at;
+        insert(
+            &mut refresh_tokens,
+            state,
+            granted,
+            family,
+            family_expires_at,
+        )
+    } else {
+        refresh_token.expires_at = refresh_token
+            .family_expires_at
+            .min(now + state.config.refresh_token_idle_lifetime);
+        value.to_string()
+    };
+    drop(refresh_tokens);
+
+    Ok(token::token_response(state, &authorization, Some(renewed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::token::create_token;
+    use actix_web::{http, test, web, App, Error};
+    use biscuit::jws::Secret;
+    use serde_json::Value;
+
+    fn create_state(refresh_token_rotation: bool) -> web::Data<AppState> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let exposed_host = "http://localhost:8080".to_string();
+        let config = Config {
+            refresh_token_rotation,
+            ..Config::default()
+        };
+        web::Data::new(AppState::with_config(rsa_keys, exposed_host, config))
+    }
+
+    fn create_authorization(scope: &str) -> Authorization {
+        Authorization {
+            client_id: "fakeidp".to_string(),
+            sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+            name: "Arie Ministrone".to_string(),
+            scope: scope.to_string(),
+            nonce: None,
+            ..Authorization::default()
+        }
+    }
+
+    fn create_refresh_request(refresh_token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(format!(
+                "grant_type=refresh_token&client_id=fakeidp&refresh_token={}",
+                refresh_token
+            ))
+    }
+
+    #[actix_rt::test]
+    async fn test_issue_requires_offline_access() {
+        let state = create_state(false);
+        assert_eq!(issue(&state, &create_authorization("openid profile")), None);
+        assert!(issue(&state, &create_authorization("openid offline_access")).is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_route_refresh_token_rotation() -> Result<(), Error> {
+        let state = create_state(true);
+        let first = issue(&state, &create_authorization("openid offline_access")).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(web::resource("/").route(web::post().to(create_token))),
+        )
+        .await;
+
+        let resp = test::call_service(&app, create_refresh_request(&first).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let p: Value = test::read_body_json(resp).await;
+        let second = p["refresh_token"].as_str().unwrap().to_string();
+        assert_ne!(first, second);
+
+        // Reusing the rotated token revokes the whole family, including the new token.
+        let resp = test::call_service(&app, create_refresh_request(&first).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let resp = test::call_service(&app, create_refresh_request(&second).to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let p: Value = test::read_body_json(resp).await;
+        assert_eq!(p["error"], "invalid_grant");
+
+        Ok(())
+    }
+}
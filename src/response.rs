@@ -0,0 +1,180 @@
+use crate::auth::{escape_html, hidden_field};
+use crate::token;
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use serde_json::json;
+
+/// The way the parameters of an authorization response are returned to the client,
+/// see OAuth 2.0 Multiple Response Type Encoding Practices and OAuth 2.0 Form Post Response Mode.
+/// The JWT variants are the JWT Secured Authorization Response Modes (JARM), they return a single signed response parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseMode {
+    Query,
+    Fragment,
+    FormPost,
+    QueryJwt,
+    FragmentJwt,
+    FormPostJwt,
+}
+
+pub const RESPONSE_MODES: [&str; 7] = [
+    "query",
+    "fragment",
+    "form_post",
+    "jwt",
+    "query.jwt",
+    "fragment.jwt",
+    "form_post.jwt",
+];
+
+/// Lifetime in seconds of a JARM response.
+pub const JARM_LIFETIME: u64 = 600;
+
+impl ResponseMode {
+    /// The response_mode of an authorization request, or the default of its response_type when none is given.
+    pub fn from_request(response_mode: Option<&str>, response_type: &str) -> Result<Self, String> {
+        let returns_tokens = returns_tokens(response_type);
+        match response_mode.filter(|mode| !mode.is_empty()) {
+            None => Ok(Self::default_for(response_type)),
+            // Tokens in the query would end up in server logs and the browser history.
+            Some(mode @ ("query" | "query.jwt")) if returns_tokens => Err(format!(
+                "response_mode {} is not allowed for response_type {}",
+                mode, response_type
+            )),
+            Some("query") => Ok(ResponseMode::Query),
+            Some("fragment") => Ok(ResponseMode::Fragment),
+            Some("form_post") => Ok(ResponseMode::FormPost),
+            Some("jwt") if returns_tokens => Ok(ResponseMode::FragmentJwt),
+            Some("jwt") | Some("query.jwt") => Ok(ResponseMode::QueryJwt),
+            Some("fragment.jwt") => Ok(ResponseMode::FragmentJwt),
+            Some("form_post.jwt") => Ok(ResponseMode::FormPostJwt),
+            Some(other) => Err(format!("response_mode {} is not supported", other)),
+        }
+    }
+
+    /// Responses with tokens default to the fragment, others to the query.
+    pub fn default_for(response_type: &str) -> Self {
+        if returns_tokens(response_type) {
+            ResponseMode::Fragment
+        } else {
+            ResponseMode::Query
+        }
+    }
+
+    /// The way a JARM response is delivered, or None when the response is not a JWT.
+    fn jwt_delivery(self) -> Option<Self> {
+        match self {
+            ResponseMode::QueryJwt => Some(ResponseMode::Query),
+            ResponseMode::FragmentJwt => Some(ResponseMode::Fragment),
+            ResponseMode::FormPostJwt => Some(ResponseMode::FormPost),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the authorization response parameters to the redirect_uri of the client.
+/// For the JARM response modes the parameters are signed as JWT for the client, see JARM section 2.1.
+pub fn respond(
+    state: &AppState,
+    client_id: &str,
+    redirect_uri: &str,
+    mode: ResponseMode,
+    params: &[(&str, &str)],
+) -> HttpResponse {
+    match mode.jwt_delivery() {
+        Some(delivery) => {
+            let iat = token::now();
+            let mut claims = json!(
+                {
+                    "iss": state.exposed_host,
+                    "aud": client_id,
+                    "iat": iat,
+                    "exp": iat + JARM_LIFETIME
+                }
+            );
+            for (name, value) in params {
+                claims[*name] = json!(value);
+            }
+            let response = token::create_jwt(&state.rsa_key_pair, claims);
+            deliver(redirect_uri, delivery, &[("response", &response)])
+        }
+        None => deliver(redirect_uri, mode, params),
+    }
+}
+
+fn deliver(redirect_uri: &str, mode: ResponseMode, params: &[(&str, &str)]) -> HttpResponse {
+    let encoded = serde_urlencoded::to_string(params).unwrap_or_default();
+    match mode {
+        ResponseMode::Query | ResponseMode::QueryJwt => {
+            let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+            redirect(format!("{}{}{}", redirect_uri, separator, encoded))
+        }
+        ResponseMode::Fragment | ResponseMode::FragmentJwt => {
+            redirect(format!("{}#{}", redirect_uri, encoded))
+        }
+        ResponseMode::FormPost | ResponseMode::FormPostJwt => {
+            let hidden_fields: String = params
+                .iter()
+                .map(|(name, value)| hidden_field(name, value))
+                .collect();
+            let body = format!(
+                include_str!("../template/form_post.html"),
+                redirect_uri = escape_html(redirect_uri),
+                hidden_fields = hidden_fields
+            );
+            HttpResponse::build(StatusCode::OK)
+                .content_type("text/html; charset=utf-8")
+                .insert_header(("Cache-Control", "no-store"))
+                .body(body)
+        }
+    }
+}
+
+fn returns_tokens(response_type: &str) -> bool {
+    response_type
+        .split_whitespace()
+        .any(|t| t == "token" || t == "id_token")
+}
+
+fn redirect(location: String) -> HttpResponse {
+    HttpResponse::build(StatusCode::SEE_OTHER)
+        .insert_header(("Location", location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_default_response_modes() {
+        assert_eq!(
+            ResponseMode::from_request(None, "code"),
+            Ok(ResponseMode::Query)
+        );
+        assert_eq!(
+            ResponseMode::from_request(None, "id_token token"),
+            Ok(ResponseMode::Fragment)
+        );
+        assert_eq!(
+            ResponseMode::from_request(Some("form_post"), "code"),
+            Ok(ResponseMode::FormPost)
+        );
+        assert!(ResponseMode::from_request(Some("query"), "token").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_respond_form_post() {
+        let resp = deliver(
+            "http://localhost:3000/callback",
+            ResponseMode::FormPost,
+            &[("code", "a-code"), ("state", "a\"state")],
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("action=\"http://localhost:3000/callback\""));
+        assert!(body.contains("name=\"state\" value=\"a&quot;state\""));
+    }
+}
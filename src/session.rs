@@ -0,0 +1,222 @@
+use crate::token;
+use crate::AppState;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{web, HttpRequest, HttpResponse};
+use data_encoding::HEXLOWER;
+use ring::digest;
+use serde_json::json;
+
+/// Name of the cookie that refers the browser to its session at fakeidp.
+pub const SESSION_COOKIE: &str = "fakeidp_session";
+
+/// The login of a browser at fakeidp, kept server side and referred to by the session cookie.
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// Session id that is given to the clients, unlike the id of the cookie it is no secret.
+    pub sid: String,
+    pub sub: String,
+    pub name: String,
+    pub auth_time: u64,
+    // Moment of the last authorization request of the browser, for the idle timeout.
+    pub last_used: u64,
+    // Authentication context and methods the tester picked on the login page.
+    pub acr: Option<String>,
+    pub amr: Vec<String>,
+    // Clients the user consented to during this session.
+    pub consents: Vec<String>,
+    // Clients that were authorized in this session, they are told when the user logs out.
+    pub clients: Vec<String>,
+}
+
+/// The session the cookie of the request refers to, together with its id.
+/// Using the session extends it until the idle timeout, an expired session is not found.
+pub fn current(state: &AppState, req: &HttpRequest) -> Option<(String, Session)> {
+    find(state, req, true)
+}
+
+fn find(state: &AppState, req: &HttpRequest, used: bool) -> Option<(String, Session)> {
+    let cookie = req.cookie(SESSION_COOKIE)?;
+    let now = token::now();
+    let mut sessions = state.sessions.lock().unwrap();
+    let session = sessions
+        .get_mut(cookie.value())
+        .filter(|session| !is_expired(state, session, now))?;
+    if used {
+        session.last_used = now;
+    }
+    Some((cookie.value().to_string(), session.clone()))
+}
+
+/// Whether the session reached its lifetime or was not used within the idle timeout.
+fn is_expired(state: &AppState, session: &Session, now: u64) -> bool {
+    now >= session.auth_time + state.config.session_lifetime
+        || now >= session.last_used + state.config.session_idle_timeout
+}
+
+/// Removes the sessions that expired and returns them, so their clients can be told.
+pub fn end_expired(state: &AppState) -> Vec<Session> {
+    let now = token::now();
+    let mut sessions = state.sessions.lock().unwrap();
+    let ids: Vec<String> = sessions
+        .iter()
+        .filter(|(_, session)| is_expired(state, session, now))
+        .map(|(id, _)| id.clone())
+        .collect();
+    ids.iter().filter_map(|id| sessions.remove(id)).collect()
+}
+
+/// Starts a new session for the user that just logged in, and returns its id with the cookie for the browser.
+pub fn start(
+    state: &AppState,
+    sub: String,
+    name: String,
+    acr: Option<String>,
+    amr: Vec<String>,
+) -> (String, Session, Cookie<'static>) {
+    let id = nanoid::nanoid!(32);
+    let now = token::now();
+    let session = Session {
+        sid: nanoid::nanoid!(),
+        sub,
+        name,
+        auth_time: now,
+        last_used: now,
+        acr,
+        amr,
+        consents: Vec::new(),
+        clients: Vec::new(),
+    };
+    state
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(id.clone(), session.clone());
+    let cookie = cookie(state, id.clone());
+    (id, session, cookie)
+}
+
+/// Renews the session when its user logs in again, like for prompt=login, max_age or a higher acr.
+/// The sid stays the same, so the clients of the session are not logged out.
+pub fn reauthenticate(
+    state: &AppState,
+    id: &str,
+    name: String,
+    acr: Option<String>,
+    amr: Vec<String>,
+) -> Option<Session> {
+    let mut sessions = state.sessions.lock().unwrap();
+    let session = sessions.get_mut(id)?;
+    let now = token::now();
+    session.name = name;
+    session.auth_time = now;
+    session.last_used = now;
+    session.acr = acr;
+    session.amr = amr;
+    Some(session.clone())
+}
+
+/// The session cookie with the given session id, an empty id is used to remove the cookie.
+/// Over https it is sent to the check_session_iframe embedded by a client on another site as well.
+pub fn cookie(state: &AppState, id: String) -> Cookie<'static> {
+    let secure = state.exposed_host.starts_with("https://");
+    Cookie::build(SESSION_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(if secure {
+            SameSite::None
+        } else {
+            SameSite::Lax
+        })
+        .finish()
+}
+
+/// The session_state of OIDC Session Management section 3 for the client at the origin of the redirect_uri.
+/// The browser state is the sid, the check_session_iframe calculates the same hash from the browser_state endpoint.
+pub fn session_state(session: &Session, client_id: &str, redirect_uri: &str) -> String {
+    let origin = reqwest::Url::parse(redirect_uri)
+        .map(|uri| uri.origin().ascii_serialization())
+        .unwrap_or_default();
+    let salt = nanoid::nanoid!(16);
+    let state = format!("{} {} {} {}", client_id, origin, session.sid, salt);
+    let hash = digest::digest(&digest::SHA256, state.as_bytes());
+    format!("{}.{}", HEXLOWER.encode(hash.as_ref()), salt)
+}
+
+/// Ends the session, the user has to log in again.
+pub fn end(state: &AppState, id: &str) -> Option<Session> {
+    state.sessions.lock().unwrap().remove(id)
+}
+
+/// Ends every session of the sub or with the sid, for a logout that is not started by the browser.
+pub fn end_all(state: &AppState, sub: Option<&str>, sid: Option<&str>) -> Vec<Session> {
+    let mut sessions = state.sessions.lock().unwrap();
+    let ids: Vec<String> = sessions
+        .iter()
+        .filter(|(_, session)| {
+            sub.is_none_or(|sub| session.sub == sub) && sid.is_none_or(|sid| session.sid == sid)
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+    ids.iter().filter_map(|id| sessions.remove(id)).collect()
+}
+
+/// Remembers that the client was authorized in the session with the given sid.
+pub fn join(state: &AppState, sid: &str, client_id: &str) {
+    let mut sessions = state.sessions.lock().unwrap();
+    if let Some(session) = sessions.values_mut().find(|session| session.sid == sid) {
+        if !session.clients.iter().any(|client| client == client_id) {
+            session.clients.push(client_id.to_string());
+        }
+    }
+}
+
+/// Remembers that the user of the session consented to the client.
+pub fn consent(state: &AppState, id: &str, client_id: &str) {
+    if let Some(session) = state.sessions.lock().unwrap().get_mut(id) {
+        if !session.consents.iter().any(|consent| consent == client_id) {
+            session.consents.push(client_id.to_string());
+        }
+    }
+}
+
+/// The check_session_iframe of OIDC Session Management section 3.2.
+/// It answers unchanged or changed to the client_id and session_state a client posts to it.
+pub async fn check_session() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../template/check_session.html"))
+}
+
+/// The browser state for the check_session_iframe: the sid of the session of the browser, empty once it ended.
+/// Asking for it does not count as using the session, so a polling client does not keep it alive.
+pub async fn browser_state(state: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let browser_state = find(&state, &req, false)
+        .map(|(_, session)| session.sid)
+        .unwrap_or_default();
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(json!({ "browser_state": browser_state }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use biscuit::jws::Secret;
+
+    #[test]
+    fn test_cookie_same_site() {
+        for (exposed_host, same_site, secure) in [
+            ("http://localhost:8080", SameSite::Lax, Some(false)),
+            ("https://idp.example.com", SameSite::None, Some(true)),
+        ] {
+            let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+                .expect("Cannot read RSA keypair");
+            let state = AppState::new(rsa_keys, exposed_host.to_string());
+            let cookie = cookie(&state, "an-id".to_string());
+            assert_eq!(cookie.same_site(), Some(same_site));
+            assert_eq!(cookie.secure(), secure);
+            assert_eq!(cookie.http_only(), Some(true));
+        }
+    }
+}
@@ -1,6 +1,10 @@
+use crate::claims;
+use crate::token::{self, Authorization};
+use crate::AppState;
 use actix_4_jwt_auth::AuthenticatedUser;
-use actix_web::{Error, HttpResponse};
+use actix_web::{web, Error, HttpResponse};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use std::str;
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -9,10 +13,58 @@ pub struct FoundClaims {
     pub name: String,
     pub email: Option<String>,
     pub email_verified: Option<bool>,
+    #[serde(skip_serializing)]
+    pub jti: Option<String>,
 }
 
-pub async fn user_info(user: AuthenticatedUser<FoundClaims>) -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().json(user.claims))
+/// The claims the userinfo endpoint returns for an access token issued by one of the flows.
+pub struct ReleasedClaims {
+    pub claims: Map<String, Value>,
+    pub expires_at: u64,
+}
+
+/// Keeps the claims that are released at the userinfo endpoint for the access token with the given jti.
+/// These are the claims of the granted scopes, or with a claims request only the claims requested for userinfo.
+pub fn release(state: &AppState, jti: &str, authorization: &Authorization, expires_at: u64) {
+    let mut released = Map::new();
+    released.insert("sub".to_string(), json!(authorization.sub));
+    match authorization
+        .claims
+        .as_ref()
+        .and_then(|request| request.userinfo.as_ref())
+    {
+        Some(requested) => {
+            let available = claims::user_claims(state, authorization);
+            released.extend(claims::requested(&available, requested))
+        }
+        None => released.extend(claims::scope_claims(state, authorization)),
+    }
+
+    let now = token::now();
+    let mut userinfo = state.userinfo.lock().unwrap();
+    userinfo.retain(|_, released| released.expires_at >= now);
+    userinfo.insert(
+        jti.to_string(),
+        ReleasedClaims {
+            claims: released,
+            expires_at,
+        },
+    );
+}
+
+/// Returns the released claims of an access token from a flow, or the claims of any other valid token as they are.
+pub async fn user_info(
+    state: web::Data<AppState>,
+    user: AuthenticatedUser<FoundClaims>,
+) -> Result<HttpResponse, Error> {
+    let released = user.claims.jti.as_ref().and_then(|jti| {
+        let userinfo = state.userinfo.lock().unwrap();
+        userinfo.get(jti).map(|released| released.claims.clone())
+    });
+    match released {
+        Some(claims) => Ok(HttpResponse::Ok().json(claims)),
+        None => Ok(HttpResponse::Ok().json(user.claims)),
+    }
 }
 
 #[cfg(test)]
@@ -34,6 +86,11 @@ mod tests {
         Oidc::new(OidcConfig::Jwks(jwk_set)).await.unwrap()
     }
 
+    fn create_state(secret: &Secret) -> web::Data<AppState> {
+        let exposed_host = "http://localhost:8080".to_string();
+        web::Data::new(AppState::new(secret.clone(), exposed_host))
+    }
+
     fn create_validator(issuer: String) -> OidcBiscuitValidator {
         OidcBiscuitValidator {
             options: ValidationOptions {
@@ -70,6 +127,7 @@ mod tests {
 
         let app = test::init_service(
             App::new()
+                .app_data(create_state(&rsa_keys))
                 .app_data(oidc.clone())
                 .wrap(biscuit_validator.clone())
                 .service(web::resource("/").route(web::post().to(user_info))),
@@ -87,10 +145,8 @@ mod tests {
         assert_eq!(resp.status(), http::StatusCode::OK);
 
         let response_body = test::read_body(resp).await;
-        let body_str = match str::from_utf8(&response_body) {
-            Ok(v) => v,
-            Err(_e) => "Error with parsing result from bytes to string",
-        };
+        let body_str = str::from_utf8(&response_body)
+            .unwrap_or("Error with parsing result from bytes to string");
         let p: Value = serde_json::from_str(body_str).unwrap();
 
         assert_eq!(p["name"], json!("Arie Ministrone"));
@@ -108,6 +164,7 @@ mod tests {
 
         let app = test::init_service(
             App::new()
+                .app_data(create_state(&rsa_keys))
                 .app_data(oidc.clone())
                 .wrap(biscuit_validator.clone())
                 .service(web::resource("/").route(web::post().to(user_info))),
@@ -128,4 +185,51 @@ mod tests {
         );
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn test_route_userinfo_requested_claims() -> Result<(), Error> {
+        let rsa_keys = Secret::rsa_keypair_from_file("./keys/private_key.der")
+            .expect("Cannot read RSA keypair");
+        let state = create_state(&rsa_keys);
+        let oidc = create_oidc(&rsa_keys).await;
+        let biscuit_validator = create_validator("http://localhost:8080".to_string());
+        let access_token = token::create_access_token(
+            &state,
+            &Authorization {
+                client_id: "fakeidp".to_string(),
+                sub: "F82E617D-DEAF-4EE6-8F96-CF3409060CA2".to_string(),
+                name: "Arie Ministrone".to_string(),
+                scope: "openid".to_string(),
+                claims: Some(
+                    claims::ClaimsRequest::parse(
+                        r#"{"userinfo": {"email": {"value": "arie@example.com"}}}"#,
+                    )
+                    .unwrap(),
+                ),
+                ..Authorization::default()
+            },
+        );
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(oidc.clone())
+                .wrap(biscuit_validator.clone())
+                .service(web::resource("/").route(web::get().to(user_info))),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Authorization", format!("Bearer {}", access_token)))
+            .to_request();
+        let p: Value = test::call_and_read_body_json(&app, req).await;
+
+        // Only the requested claim is released, next to the sub that is always there.
+        assert_eq!(
+            p,
+            json!({"sub": "F82E617D-DEAF-4EE6-8F96-CF3409060CA2", "email": "arie@example.com"})
+        );
+
+        Ok(())
+    }
 }